@@ -0,0 +1,130 @@
+use crate::chat::{GptChat, History};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const MAIN_BRANCH: &str = "main";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Branch {
+    pub chat: GptChat,
+    // Branch this one was started from and how many messages they share
+    pub parent: Option<String>,
+    pub fork_point: usize,
+}
+
+// Alternative chat histories that start from the same point in a conversation.
+// The chat of the current branch is owned by the caller, it is stored here on checkout.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Branches {
+    current: String,
+    branches: BTreeMap<String, Branch>,
+}
+
+impl Default for Branches {
+    fn default() -> Branches {
+        Branches {
+            current: String::from(MAIN_BRANCH),
+            branches: BTreeMap::new(),
+        }
+    }
+}
+
+impl Branches {
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    fn store(&mut self, chat: &GptChat) {
+        match self.branches.get_mut(&self.current) {
+            Some(branch) => branch.chat = chat.clone(),
+            None => {
+                self.branches.insert(
+                    self.current.clone(),
+                    Branch {
+                        chat: chat.clone(),
+                        parent: None,
+                        fork_point: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    // Starts a branch from the current chat and switches to it
+    pub fn branch(&mut self, name: &str, chat: &GptChat) -> Result<(), String> {
+        if name == self.current || self.branches.contains_key(name) {
+            return Err(format!("branch {} already exists", name));
+        }
+        self.store(chat);
+        self.branches.insert(
+            name.to_string(),
+            Branch {
+                chat: chat.clone(),
+                parent: Some(self.current.clone()),
+                fork_point: chat.get_all().len(),
+            },
+        );
+        self.current = name.to_string();
+        Ok(())
+    }
+
+    // Stores the current chat and returns the chat of the branch switched to
+    pub fn checkout(&mut self, name: &str, chat: &GptChat) -> Result<GptChat, String> {
+        if name == self.current {
+            return Ok(chat.clone());
+        }
+        let target = match self.branches.get(name) {
+            Some(branch) => branch.chat.clone(),
+            None => return Err(format!("there is no branch named {}", name)),
+        };
+        self.store(chat);
+        self.current = name.to_string();
+        Ok(target)
+    }
+
+    // Every branch, with the current one taken from `chat`
+    pub fn list(&self, chat: &GptChat) -> Vec<(String, Branch)> {
+        let mut branches = self.branches.clone();
+        let current = branches
+            .entry(self.current.clone())
+            .or_insert_with(|| Branch {
+                chat: chat.clone(),
+                parent: None,
+                fork_point: 0,
+            });
+        current.chat = chat.clone();
+        branches.into_iter().collect()
+    }
+}
+
+#[test]
+fn test_branches() {
+    use crate::chat::Message;
+
+    let message = |content: &str| Message {
+        role: Some(String::from("user")),
+        content: Some(content.to_string()),
+    };
+    let mut branches = Branches::default();
+    let mut chat = GptChat::new();
+    chat.add(message("shared"));
+
+    branches.branch("alt", &chat).unwrap();
+    assert_eq!(branches.current(), "alt");
+    assert!(branches.branch("main", &chat).is_err());
+    chat.add(message("alt follow up"));
+
+    let mut chat = branches.checkout("main", &chat).unwrap();
+    assert_eq!(chat.get_all().len(), 1);
+    chat.add(message("main follow up"));
+
+    let chat = branches.checkout("alt", &chat).unwrap();
+    assert_eq!(chat.get_all()[1].content.as_deref(), Some("alt follow up"));
+    assert!(branches.checkout("missing", &chat).is_err());
+
+    let list = branches.list(&chat);
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].0, "alt");
+    assert_eq!(list[0].1.parent.as_deref(), Some("main"));
+    assert_eq!(list[0].1.fork_point, 1);
+}
@@ -1,17 +1,25 @@
 use crate::err::ApiError;
-use crate::http_client;
-use crate::output::Output;
+use crate::http_client::OpenAiClient;
+use crate::output::{trim_blank_lines, Output};
+use crate::render::{color_enabled, render_markdown};
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
 use serde_json::Result as SerdeResult;
+use std::collections::HashMap;
+use std::fmt;
+
+const CHAT_COMPLETIONS_PATH: &str = "/v1/chat/completions";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GptChat {
+    // Pinned at the head of the history, kept by `flush`
+    #[serde(default)]
+    system: Option<Message>,
     messages: Vec<Message>,
 }
 
 pub trait History {
-    fn new() -> GptChat;
+    fn new() -> Self;
     fn add(&mut self, message: Message);
     fn pop(&mut self);
     fn get_all(&self) -> Vec<Message>;
@@ -20,7 +28,10 @@ pub trait History {
 
 impl History for GptChat {
     fn new() -> GptChat {
-        GptChat { messages: vec![] }
+        GptChat {
+            system: None,
+            messages: vec![],
+        }
     }
 
     fn add(&mut self, message: Message) {
@@ -32,7 +43,11 @@ impl History for GptChat {
     }
 
     fn get_all(&self) -> Vec<Message> {
-        self.messages.clone()
+        self.system
+            .iter()
+            .chain(self.messages.iter())
+            .cloned()
+            .collect()
     }
 
     fn flush(&mut self) {
@@ -40,25 +55,164 @@ impl History for GptChat {
     }
 }
 
+impl GptChat {
+    // Sets the system message sent before the rest of the history, None removes it
+    pub fn set_system(&mut self, content: Option<String>) {
+        self.system = content.map(|content| Message {
+            role: Some(String::from("system")),
+            content: Some(content),
+        });
+    }
+
+    pub fn system(&self) -> Option<&str> {
+        self.system
+            .as_ref()
+            .and_then(|message| message.content.as_deref())
+    }
+
+    // Clears the history along with the system message
+    pub fn reset(&mut self) {
+        self.flush();
+        self.system = None;
+    }
+
+    // Messages after the system message
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    // Indexes of the user messages that start each turn, not counting the system message
+    pub fn turn_starts(&self) -> Vec<usize> {
+        self.messages
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| message.role.as_deref() == Some("user"))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Removes and returns the first `count` messages after the system message
+    pub fn drain_oldest(&mut self, count: usize) -> Vec<Message> {
+        let count = count.min(self.messages.len());
+        self.messages.drain(..count).collect()
+    }
+
+    // Adds a message before the rest of the history, after the system message
+    pub fn push_front(&mut self, message: Message) {
+        self.messages.insert(0, message);
+    }
+
+    // Replaces the content of message `index` of `get_all` and drops the messages after it,
+    // the system message is replaced without touching the rest. Returns the edited message.
+    pub fn edit(&mut self, index: usize, content: String) -> Option<Message> {
+        let index = match (&mut self.system, index) {
+            (Some(system), 0) => {
+                system.content = Some(content);
+                return Some(system.clone());
+            }
+            (Some(_), index) => index - 1,
+            (None, index) => index,
+        };
+        let message = self.messages.get_mut(index)?;
+        message.content = Some(content);
+        let message = message.clone();
+        self.messages.truncate(index + 1);
+        Some(message)
+    }
+
+    // Removes the last user message and the responses to it, returning how many were removed
+    pub fn undo(&mut self) -> usize {
+        match self.turn_starts().last() {
+            Some(&start) => self.messages.drain(start..).count(),
+            None => 0,
+        }
+    }
+
+    // Removes and returns the responses to the last user message, so it can be sent again
+    pub fn take_reply(&mut self) -> Vec<Message> {
+        match self.turn_starts().last() {
+            Some(&start) => self.messages.drain(start + 1..).collect(),
+            None => vec![],
+        }
+    }
+
+    // Adds messages to the end of the history, a leading system message replaces the current one
+    pub fn extend(&mut self, messages: Vec<Message>) {
+        let mut messages = messages.into_iter().peekable();
+        if let Some(system) = messages.next_if(|m| m.role.as_deref() == Some("system")) {
+            self.system = Some(system);
+        }
+        self.messages.extend(messages);
+    }
+}
+
+const ROLES: [&str; 3] = ["system", "user", "assistant"];
+
+// Reads a chat history written by export(), every message needs a known role and content
+pub fn parse_messages(json: &str) -> Result<Vec<Message>, String> {
+    let messages: Vec<Message> =
+        from_str(json).map_err(|e| format!("not an exported chat history: {}", e))?;
+    for (index, message) in messages.iter().enumerate() {
+        match message.role.as_deref() {
+            Some(role) if ROLES.contains(&role) => {}
+            role => {
+                return Err(format!(
+                    "message {} has an unknown role {:?}",
+                    index + 1,
+                    role.unwrap_or("")
+                ))
+            }
+        }
+        if message.content.is_none() {
+            return Err(format!("message {} has no content", index + 1));
+        }
+    }
+    Ok(messages)
+}
+
 // Based off create chat completion
 // See API reference here https://platform.openai.com/docs/api-reference/chat/create
-#[derive(Debug, Deserialize, Serialize)]
+// Options that are not set are left out of the request so the API defaults apply
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ChatCreateCompletionParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub messages: Option<Vec<Message>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<i32>,
-    //TODO: readd
-    // stop: Option<Vec<String>>,
-    // stream: Option<bool>,
-    // n: Option<i32>,
-    // top_n: Option<f64>,
-    // presence_penalty: Option<f64>,
-    // frequency_penalty: Option<f64>,
-    // user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    // Maps token ids to a bias from -100 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Either "text" or "json_object"
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ResponseFormat {
+    pub r#type: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct ChatCreateCompletionResponse {
     id: String,
     object: Option<String>,
@@ -88,7 +242,7 @@ struct Usage {
     total_tokens: Option<i32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Error {
     pub message: String,
     pub r#type: String,
@@ -96,6 +250,19 @@ pub struct Error {
     pub code: Option<String>,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}", self.message, self.r#type)?;
+        if let Some(code) = &self.code {
+            write!(f, ", code: {}", code)?;
+        }
+        if let Some(param) = &self.param {
+            write!(f, ", param: {}", param)?;
+        }
+        write!(f, ")")
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ErrorResponse {
     pub error: Error,
@@ -108,95 +275,405 @@ pub enum Response {
     Error(ErrorResponse),
 }
 
+// A single server-sent event received when `stream` is set
+// See https://platform.openai.com/docs/api-reference/chat/streaming
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatCreateCompletionChunk {
+    id: String,
+    object: Option<String>,
+    created: Option<i64>,
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ChunkChoice {
+    delta: Message,
+    index: Option<i32>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StreamResponse {
+    ChatCreateCompletionChunk(ChatCreateCompletionChunk),
+    Error(ErrorResponse),
+}
+
+impl ChatCreateCompletionResponse {
+    // Merges a streamed chunk into the response, appending each delta to the choice with the same index
+    fn add_chunk(&mut self, chunk: ChatCreateCompletionChunk) {
+        self.id = chunk.id;
+        self.object = chunk.object;
+        self.created_at = chunk.created;
+        let choices = self.choices.get_or_insert_with(Vec::new);
+        for chunk_choice in chunk.choices {
+            let index = chunk_choice.index.unwrap_or(0);
+            let position = match choices.iter().position(|c| c.index == Some(index)) {
+                Some(position) => position,
+                None => {
+                    choices.push(Choice {
+                        message: Some(Message {
+                            role: None,
+                            content: Some(String::new()),
+                        }),
+                        index: Some(index),
+                        logprobs: None,
+                        finish_reason: None,
+                    });
+                    choices.len() - 1
+                }
+            };
+            let choice = &mut choices[position];
+            if let Some(message) = choice.message.as_mut() {
+                if chunk_choice.delta.role.is_some() {
+                    message.role = chunk_choice.delta.role;
+                }
+                if let Some(delta) = chunk_choice.delta.content {
+                    message
+                        .content
+                        .get_or_insert_with(String::new)
+                        .push_str(&delta);
+                }
+            }
+            if chunk_choice.finish_reason.is_some() {
+                choice.finish_reason = chunk_choice.finish_reason;
+            }
+        }
+    }
+}
+
+impl ChatCreateCompletionResponse {
+    // Messages of each choice in index order, when `n` is more than 1 these are the candidates
+    // to pick from
+    pub fn candidates(&self) -> Vec<Message> {
+        let mut choices: Vec<&Choice> = self.choices.iter().flatten().collect();
+        choices.sort_by_key(|choice| choice.index.unwrap_or(0));
+        choices
+            .into_iter()
+            .filter_map(|choice| choice.message.clone())
+            .collect()
+    }
+}
 
 impl Output for ChatCreateCompletionResponse {
+    // Candidates are numbered when there is more than one choice
     fn get_output(&self) -> String {
+        let candidates = self.candidates();
         let mut output = String::from("");
-        for choice in self.choices.iter() {
-            for message in choice.iter() {
-                let lines = &message.message;
-                let some_lines = lines;
-                match some_lines {
-                    Some(some_lines) => {
-                        for line in &some_lines.content {
-                            if line.trim().is_empty() {
-                                continue; // ignore empty or whitespace-only lines
-                            }
-                            output.push_str(&line.to_string());
-                            output.push_str("\n");
-                        }
-                    }
-                    None => {}
+        for (number, message) in candidates.iter().enumerate() {
+            if let Some(content) = &message.content {
+                if content.trim().is_empty() {
+                    continue; // ignore empty or whitespace-only responses
                 }
+                if candidates.len() > 1 {
+                    output.push_str(&format!("[{}] ", number + 1));
+                }
+                output.push_str(trim_blank_lines(content));
+                output.push('\n');
             }
         }
         output
     }
+
+    // Replies are markdown, so they are rendered rather than printed in one color
+    fn to_cli(&self) {
+        print!("{}", render_markdown(&self.get_output(), color_enabled()))
+    }
 }
 
 impl Output for ErrorResponse {
     fn get_output(&self) -> String {
-        let output = format!("{:?}", self);
-        String::from(output)
+        format!("{:?}", self)
     }
 }
 
 pub trait MessageHistory {
-    fn save_messages(&self, history: &mut GptChat);
+    // Returns whether a message was added
+    fn save_messages(&self, history: &mut GptChat) -> bool;
 }
 
 impl MessageHistory for ChatCreateCompletionResponse {
-    fn save_messages(&self, history: &mut GptChat) {
-        for choice in self.choices.iter() {
-            for message in choice.iter() {
-                let lines = &message.message;
-                let some_lines = lines;
-                match some_lines {
-                    Some(some_lines) => history.add(Message {
-                        role: some_lines.role.clone(),
-                        content: some_lines.content.clone(),
-                    }),
-                    None => {}
-                }
+    // Only the first choice is saved, the others are left to be picked from `candidates`
+    fn save_messages(&self, history: &mut GptChat) -> bool {
+        match self.candidates().into_iter().next() {
+            Some(message) => {
+                history.add(message);
+                true
             }
+            None => false,
         }
     }
 }
 
 fn parse_chat_response(response: String) -> SerdeResult<Response> {
-    match from_str(&response) {
-        //TODO: make sure decoding is happining here properly
-        Ok(c) => return Ok(c),
-        Err(e) => {
-            return Err(e);
+    //TODO: make sure decoding is happining here properly
+    from_str(&response)
+}
+
+impl OpenAiClient {
+    pub async fn chat(
+        &self,
+        request_defaults: ChatCreateCompletionParams,
+    ) -> Result<ChatCreateCompletionResponse, ApiError> {
+        //TODO: Readd Language
+        let response = self.post(CHAT_COMPLETIONS_PATH, &request_defaults).await?;
+        match parse_chat_response(response)? {
+            Response::ChatCreateCompletion(r) => Ok(r),
+            Response::Error(e) => Err(ApiError::OpenAi {
+                status: None,
+                error: e.error,
+            }),
         }
+    }
+
+    // Streams the response, calling `on_delta` with each piece of content as it arrives.
+    // The returned response holds the assembled messages so it can be saved to the history.
+    pub async fn chat_stream<F: FnMut(&str)>(
+        &self,
+        mut request_defaults: ChatCreateCompletionParams,
+        mut on_delta: F,
+    ) -> Result<ChatCreateCompletionResponse, ApiError> {
+        request_defaults.stream = Some(true);
+        let mut response = ChatCreateCompletionResponse::default();
+        self.post_stream(
+            CHAT_COMPLETIONS_PATH,
+            &request_defaults,
+            |event| match from_str::<StreamResponse>(event)? {
+                StreamResponse::ChatCreateCompletionChunk(chunk) => {
+                    for choice in chunk.choices.iter() {
+                        if let Some(content) = &choice.delta.content {
+                            on_delta(content);
+                        }
+                    }
+                    response.add_chunk(chunk);
+                    Ok(())
+                }
+                StreamResponse::Error(e) => Err(ApiError::OpenAi {
+                    status: None,
+                    error: e.error,
+                }),
+            },
+        )
+        .await?;
+        Ok(response)
+    }
+}
+
+#[tokio::test]
+async fn test_chat() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token").with_base_url(&server.url());
+    let request_base = ChatCreateCompletionParams {
+        model: Some(String::from("fake-model")),
+        messages: Some(vec![]),
+        max_tokens: Some(3000),
+        temperature: Some(0.7),
+        ..Default::default()
     };
+
+    let mock = server
+        .mock("POST", "/v1/chat/completions")
+        .match_header("authorization", "Bearer test-token")
+        .match_body(mockito::Matcher::PartialJson(
+            serde_json::json!({"model": "fake-model"}),
+        ))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{"id": "1", "choices": [{"message": {"role": "assistant", "content": "hi"}}]}"#,
+        )
+        .create_async()
+        .await;
+
+    let response = client.chat(request_base).await.unwrap();
+
+    mock.assert_async().await;
+    assert_eq!(response.get_output(), "hi\n");
+}
+
+#[test]
+fn test_add_chunk_assembles_message() {
+    let mut response = ChatCreateCompletionResponse::default();
+    for event in [
+        r#"{"id":"1","choices":[{"index":0,"delta":{"role":"assistant"}}]}"#,
+        r#"{"id":"1","choices":[{"index":0,"delta":{"content":"Hello"}}]}"#,
+        r#"{"id":"1","choices":[{"index":0,"delta":{"content":" world"},"finish_reason":"stop"}]}"#,
+    ] {
+        response.add_chunk(from_str(event).unwrap());
+    }
+    let mut history = GptChat::new();
+    assert!(response.save_messages(&mut history));
+    let messages = history.get_all();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].role.as_deref(), Some("assistant"));
+    assert_eq!(messages[0].content.as_deref(), Some("Hello world"));
+}
+
+#[test]
+fn test_multiple_choices() {
+    let response: ChatCreateCompletionResponse = from_str(
+        r#"{"id": "1", "choices": [
+        {"index": 1, "message": {"role": "assistant", "content": "second"}},
+        {"index": 0, "message": {"role": "assistant", "content": "first"}}]}"#,
+    )
+    .unwrap();
+    assert_eq!(response.get_output(), "[1] first\n[2] second\n");
+
+    let mut history = GptChat::new();
+    response.save_messages(&mut history);
+    let messages = history.get_all();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content.as_deref(), Some("first"));
+
+    // A reply without choices, or a stream that only sent [DONE], adds nothing
+    let empty: ChatCreateCompletionResponse = from_str(r#"{"id": "1", "choices": []}"#).unwrap();
+    assert!(!empty.save_messages(&mut history));
+    assert!(!ChatCreateCompletionResponse::default().save_messages(&mut history));
+    assert_eq!(history.get_all().len(), 1);
+}
+
+#[test]
+fn test_output_keeps_blank_lines() {
+    let response: ChatCreateCompletionResponse = from_str(
+        r#"{"id": "1", "choices": [{"message": {"role": "assistant",
+        "content": "\n\nA paragraph.\n\n```rust\nfn a() {}\n\nfn b() {}\n```\n"}}]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        response.get_output(),
+        "A paragraph.\n\n```rust\nfn a() {}\n\nfn b() {}\n```\n"
+    );
 }
 
-pub async fn process_chat_prompt(
-    request_defaults: ChatCreateCompletionParams,
-) -> Result<ChatCreateCompletionResponse, ApiError> {
-    //TODO: Readd Language
-    let result = http_client::send_chat_request(request_defaults).await;
+#[tokio::test]
+async fn test_chat_stream_error_event() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token").with_base_url(&server.url());
+    let request_base = ChatCreateCompletionParams {
+        model: Some(String::from("fake-model")),
+        messages: Some(vec![]),
+        max_tokens: Some(3000),
+        temperature: Some(0.7),
+        ..Default::default()
+    };
+
+    let mock = server
+        .mock("POST", "/v1/chat/completions")
+        .with_status(200)
+        .with_header("content-type", "text/event-stream")
+        .with_body("data: {\"error\": {\"message\": \"overloaded\", \"type\": \"server_error\", \"param\": null, \"code\": null}}\n\n")
+        .create_async()
+        .await;
+
+    let result = client.chat_stream(request_base, |_| {}).await;
+
+    mock.assert_async().await;
     match result {
-        Ok(response) => match parse_chat_response(response) {
-            Ok(completion) => {
-                match completion {
-                    Response::ChatCreateCompletion(r)  => {
-                        return Ok(r);
-                    }
-                    Response::Error(e) => {
-                        return Err(ApiError::new(&e.get_output()));
-                    }
+        Err(ApiError::OpenAi { error, .. }) => assert_eq!(error.r#type, "server_error"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
 
-                }
-            }
-            Err(e) => {
-                return Err(ApiError::new(&e.to_string()));
-            }
-        },
-        Err(e) => {
-            return Err(ApiError::new(&e.to_string()));
-        }
+#[test]
+fn test_chat_params_skip_unset_options() {
+    let request = ChatCreateCompletionParams {
+        model: Some(String::from("gpt-3.5-turbo")),
+        stop: Some(vec![String::from("\n")]),
+        seed: Some(42),
+        response_format: Some(ResponseFormat {
+            r#type: String::from("json_object"),
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        serde_json::to_value(&request).unwrap(),
+        serde_json::json!({
+            "model": "gpt-3.5-turbo",
+            "stop": ["\n"],
+            "seed": 42,
+            "response_format": {"type": "json_object"},
+        })
+    );
+}
+
+#[test]
+fn test_undo_and_take_reply() {
+    let mut history = GptChat::new();
+    for (role, content) in [
+        ("user", "a"),
+        ("assistant", "b"),
+        ("user", "c"),
+        ("assistant", "d"),
+    ] {
+        history.add(Message {
+            role: Some(String::from(role)),
+            content: Some(String::from(content)),
+        });
     }
+    let reply = history.take_reply();
+    assert_eq!(reply[0].content.as_deref(), Some("d"));
+    assert_eq!(history.get_all().len(), 3);
+    assert_eq!(history.undo(), 1);
+    assert_eq!(history.get_all().len(), 2);
+    assert_eq!(history.undo(), 2);
+    assert_eq!(history.undo(), 0);
+}
+
+#[test]
+fn test_edit_message() {
+    let mut history = GptChat::new();
+    history.set_system(Some(String::from("Be brief")));
+    for (role, content) in [("user", "a"), ("assistant", "b"), ("user", "c")] {
+        history.add(Message {
+            role: Some(String::from(role)),
+            content: Some(String::from(content)),
+        });
+    }
+    let edited = history.edit(1, String::from("a2")).unwrap();
+    assert_eq!(edited.role.as_deref(), Some("user"));
+    assert_eq!(history.get_all().len(), 2);
+    history.edit(0, String::from("Be long")).unwrap();
+    assert_eq!(history.system(), Some("Be long"));
+    assert_eq!(history.get_all().len(), 2);
+    assert!(history.edit(5, String::new()).is_none());
+}
+
+#[test]
+fn test_import_messages() {
+    let messages = parse_messages(
+        r#"[{"role": "system", "content": "Be brief"}, {"role": "user", "content": "hi"},
+        {"role": "assistant", "content": "hello"}]"#,
+    )
+    .unwrap();
+    let mut history = GptChat::new();
+    history.add(Message {
+        role: Some(String::from("user")),
+        content: Some(String::from("earlier")),
+    });
+    history.extend(messages);
+    assert_eq!(history.system(), Some("Be brief"));
+    assert_eq!(history.get_all().len(), 4);
+
+    assert!(parse_messages(r#"{"role": "user"}"#).is_err());
+    assert!(parse_messages(r#"[{"role": "robot", "content": "hi"}]"#).is_err());
+    assert!(parse_messages(r#"[{"role": "user"}]"#).is_err());
+}
+
+#[test]
+fn test_system_message_survives_flush() {
+    let mut history = GptChat::new();
+    history.set_system(Some(String::from("Be brief")));
+    history.add(Message {
+        role: Some(String::from("user")),
+        content: Some(String::from("hi")),
+    });
+    history.pop();
+    history.flush();
+    let messages = history.get_all();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].role.as_deref(), Some("system"));
+    assert_eq!(history.system(), Some("Be brief"));
+
+    history.reset();
+    assert!(history.get_all().is_empty());
 }
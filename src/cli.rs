@@ -1,7 +1,7 @@
-use crate::completion::CodeCompletionCreateParams;
-use clap::{arg, ArgMatches, Command};
-use std;
+use clap::{arg, ArgAction, ArgMatches, Command};
+use gptshell::completion::{split_insert, CodeCompletionCreateParams};
 use std::fs;
+use std::str::FromStr;
 
 pub struct RequestDefaults {
     pub matches: ArgMatches,
@@ -14,6 +14,8 @@ pub trait Defaults {
     fn get_model(&self) -> String;
     fn get_temperature(&self) -> f64;
     fn get_max_tokens(&self) -> i32;
+    fn get_suffix(&self) -> Option<String>;
+    fn get_stop(&self) -> Option<Vec<String>>;
     fn get_request_base(&self) -> CodeCompletionCreateParams;
 }
 
@@ -21,6 +23,21 @@ fn string_to_vec(s: &str) -> Vec<String> {
     vec![String::from(s)]
 }
 
+// Reads an optional argument, an invalid value is reported and left unset
+fn get_optional<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    let value = matches.get_one::<String>(name)?;
+    match value.parse::<T>() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            println!(
+                "{} is not a valid value for --{}, so ignoring it",
+                value, name
+            );
+            None
+        }
+    }
+}
+
 impl Defaults for RequestDefaults {
     fn get_file_path(&self) -> String {
         let file = self
@@ -47,12 +64,12 @@ impl Defaults for RequestDefaults {
             .map(|s| s.as_str())
             .unwrap();
         let file_path = self.get_file_path();
-        if file_path != "" {
+        if !file_path.is_empty() {
             let contents =
                 fs::read_to_string(file_path).expect("Should have been able to read the file");
             return string_to_vec(&format!("\\\\ {} \n{}", prompt, contents).to_string());
         }
-        string_to_vec(&prompt.to_string())
+        string_to_vec(prompt)
     }
 
     fn get_model(&self) -> String {
@@ -74,7 +91,7 @@ impl Defaults for RequestDefaults {
     }
 
     fn get_temperature(&self) -> f64 {
-        let mut temperature = f64::from(0.7);
+        let mut temperature = 0.7;
         let temperature_arg = self
             .matches
             .get_one::<String>("temperature")
@@ -98,18 +115,53 @@ impl Defaults for RequestDefaults {
         max_tokens
     }
 
+    fn get_suffix(&self) -> Option<String> {
+        self.matches.get_one::<String>("suffix").cloned()
+    }
+
+    fn get_stop(&self) -> Option<Vec<String>> {
+        let stop: Vec<String> = self
+            .matches
+            .get_many::<String>("stop")?
+            .map(|s| s.replace("\\n", "\n"))
+            .collect();
+        Some(stop)
+    }
+
     fn get_request_base(&self) -> CodeCompletionCreateParams {
+        let mut prompt = self.get_prompt();
+        let mut suffix = self.get_suffix();
+        // An insert marker in the prompt or input file takes the place of --suffix
+        if let Some(first) = prompt.first() {
+            if let (prefix, Some(marked_suffix)) = split_insert(first) {
+                prompt = string_to_vec(&prefix);
+                suffix = Some(marked_suffix);
+            }
+        }
         CodeCompletionCreateParams {
             model: self.get_model(),
             temperature: self.get_temperature(),
             max_tokens: self.get_max_tokens(),
-            prompt: self.get_prompt(),
+            prompt,
+            stream: None,
+            stop: self.get_stop(),
+            suffix,
+            echo: self.matches.get_flag("echo").then_some(true),
+            n: get_optional(&self.matches, "n"),
+            top_p: get_optional(&self.matches, "top_p"),
+            logprobs: get_optional(&self.matches, "logprobs"),
+            presence_penalty: get_optional(&self.matches, "presence_penalty"),
+            frequency_penalty: get_optional(&self.matches, "frequency_penalty"),
+            best_of: get_optional(&self.matches, "best_of"),
+            logit_bias: None,
+            user: self.matches.get_one::<String>("user").cloned(),
         }
     }
 }
 
 pub fn cli() -> Command {
     Command::new("gptshell")
+    .arg(arg!(--session <NAME> "Open the named chat session in the shell, it is started if it does not exist"))
     .subcommand( Command::new("completion")
     .args([
         arg!(--prompt <PROMPT> "Prompt to enter in chatgptm if this is included with a file it will be added to the top of the file as a comment"),
@@ -118,5 +170,15 @@ pub fn cli() -> Command {
         arg!(--max_tokens <MAX_TOKENS> "Max tokens depends on model, see --model"),
         arg!(--temperature <TEMPERATURE> "Value from 0-1, Lower temperatures give more precise results."),
         arg!(--model <MODEL> "For code completion, use `code-davinci-002` (latest beta, up to 4000 tokens) or `code-cushman-001` (up to 2048 tokens) \n for gpt models use text-davinci-003	"),
+        arg!(--suffix <SUFFIX> "Text that comes after the completion, to fill in the middle. Alternatively put [insert] in the prompt or input file where the completion should go"),
+        arg!(--stop <STOP> "Sequence where the API will stop generating, can be repeated up to 4 times").action(ArgAction::Append),
+        arg!(--echo "Echo back the prompt in addition to the completion"),
+        arg!(--n <N> "How many completions to generate"),
+        arg!(--best_of <BEST_OF> "Generates best_of completions server side and returns the best, must be greater than n"),
+        arg!(--logprobs <LOGPROBS> "Include the log probabilities of the most likely tokens, up to 5, tokens the model was unsure about are highlighted"),
+        arg!(--top_p <TOP_P> "Nucleus sampling, value from 0-1, alter this or temperature but not both"),
+        arg!(--presence_penalty <PRESENCE_PENALTY> "Value from -2 to 2, positive values penalise tokens that have already appeared"),
+        arg!(--frequency_penalty <FREQUENCY_PENALTY> "Value from -2 to 2, positive values penalise tokens by how often they have appeared"),
+        arg!(--user <USER> "Identifier for the end user"),
     ]))
 }
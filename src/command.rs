@@ -0,0 +1,381 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Parser for the REPL command language, a command is a name followed by arguments in brackets
+// i.e file("./src/main.rs", 1, 12) or truncate(keep_last, turns=4)
+//
+//   command  = name "(" [ argument { "," argument } [ "," ] ] ")"
+//   argument = [ name "=" ] value
+//   value    = string | int | float | word
+//
+// Strings are in double or single quotes and support \n \t \r \0 \\ \" and \' escapes.
+// Unquoted values can't contain whitespace, brackets, commas, quotes or =.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    // An unquoted value that is not a number i.e none or ./src/main.rs
+    Word(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) | Value::Word(s) => write!(f, "{}", s),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg {
+    pub name: Option<String>,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub name: String,
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    // Character position in the input the error was found at
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl ParseError {
+    // The input with a caret under where the error was found
+    pub fn show(&self, input: &str) -> String {
+        format!("{}\n{}^ {}", input, " ".repeat(self.position), self)
+    }
+}
+
+// Most positional arguments a command takes and the names of its named arguments
+pub type Spec = (usize, &'static [&'static str]);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Open,
+    Close,
+    Comma,
+    Equals,
+}
+
+fn error<T>(position: usize, message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError {
+        position,
+        message: message.into(),
+    })
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '(' | ')' | ',' | '=' | '"' | '\'')
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = vec![];
+    let mut position = 0;
+    while position < chars.len() {
+        let start = position;
+        let c = chars[position];
+        position += 1;
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            '=' => Token::Equals,
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    let Some(&next) = chars.get(position) else {
+                        return error(start, "this string is never closed");
+                    };
+                    position += 1;
+                    match next {
+                        '\\' => {
+                            let escaped = match chars.get(position) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('r') => '\r',
+                                Some('0') => '\0',
+                                Some(c @ ('\\' | '"' | '\'')) => *c,
+                                Some(c) => {
+                                    return error(position - 1, format!("unknown escape \\{}", c))
+                                }
+                                None => return error(start, "this string is never closed"),
+                            };
+                            value.push(escaped);
+                            position += 1;
+                        }
+                        next if next == c => break,
+                        next => value.push(next),
+                    }
+                }
+                Token::Str(value)
+            }
+            _ => {
+                while chars.get(position).map(|c| is_word_char(*c)) == Some(true) {
+                    position += 1;
+                }
+                Token::Word(chars[start..position].iter().collect())
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+fn is_name(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn word_value(word: String) -> Value {
+    if let Ok(int) = word.parse::<i64>() {
+        Value::Int(int)
+    } else if let Ok(float) = word.parse::<f64>() {
+        Value::Float(float)
+    } else {
+        Value::Word(word)
+    }
+}
+
+// Parses `input` when it starts with the name of a command that `spec` knows followed by a
+// bracket, anything else is not a command and gives None. Indented lines and commands that take
+// no arguments written with some i.e print("hi") are left alone, so pasted code stays text.
+pub fn parse<F: Fn(&str) -> Option<Spec>>(
+    input: &str,
+    spec: F,
+) -> Result<Option<Call>, ParseError> {
+    let name: String = input
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+    let Some((max_args, named)) = spec(&name) else {
+        return Ok(None);
+    };
+    if !is_name(&name) || !input[name.len()..].starts_with('(') {
+        return Ok(None);
+    }
+    let brackets: String = input[name.len()..]
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    if max_args == 0 && named.is_empty() && brackets != "()" {
+        return Ok(None);
+    }
+    let tokens = tokenize(input)?;
+    let mut args: Vec<Arg> = vec![];
+    // Skip the name and the opening bracket
+    let mut index = 2;
+    let length = input.chars().count();
+    loop {
+        let Some((position, token)) = tokens.get(index).cloned() else {
+            return error(length, "missing a closing )");
+        };
+        if token == Token::Close {
+            break;
+        }
+        if !args.is_empty() {
+            if token != Token::Comma {
+                return error(position, "expected a , between arguments");
+            }
+            index += 1;
+            if let Some((_, Token::Close)) = tokens.get(index) {
+                break;
+            }
+        }
+        let (mut position, mut token) = match tokens.get(index).cloned() {
+            Some(token) => token,
+            None => return error(length, "missing a closing )"),
+        };
+        let mut arg_name = None;
+        if let (Token::Word(word), Some((_, Token::Equals))) = (&token, tokens.get(index + 1)) {
+            if !is_name(word) {
+                return error(position, format!("{} is not a valid argument name", word));
+            }
+            arg_name = Some(word.clone());
+            index += 2;
+            (position, token) = match tokens.get(index).cloned() {
+                Some(token) => token,
+                None => return error(length, "missing a value after ="),
+            };
+        }
+        let value = match token {
+            Token::Str(value) => Value::Str(value),
+            Token::Word(word) => word_value(word),
+            _ => return error(position, "expected a value"),
+        };
+        match &arg_name {
+            Some(arg_name) if !named.contains(&arg_name.as_str()) => {
+                return error(
+                    position,
+                    format!("{}() has no argument named {}", name, arg_name),
+                )
+            }
+            Some(arg_name) if args.iter().any(|arg| arg.name.as_ref() == Some(arg_name)) => {
+                return error(position, format!("{} is given more than once", arg_name))
+            }
+            Some(_) => {}
+            None if args.iter().filter(|arg| arg.name.is_none()).count() == max_args => {
+                return error(
+                    position,
+                    match max_args {
+                        0 => format!("{}() takes no arguments", name),
+                        1 => format!(
+                            "{}() takes 1 argument, quote text that contains commas",
+                            name
+                        ),
+                        n => format!("{}() takes up to {} arguments", name, n),
+                    },
+                )
+            }
+            None => {}
+        }
+        args.push(Arg {
+            name: arg_name,
+            value,
+        });
+        index += 1;
+    }
+    if let Some((position, _)) = tokens.get(index + 1) {
+        return error(*position, format!("unexpected text after {}()", name));
+    }
+    Ok(Some(Call { name, args }))
+}
+
+impl Call {
+    // The positional argument at `index` or the argument called `name`
+    pub fn arg(&self, index: usize, name: &str) -> Option<&Value> {
+        self.args
+            .iter()
+            .find(|arg| arg.name.as_deref() == Some(name))
+            .or_else(|| self.args.iter().filter(|arg| arg.name.is_none()).nth(index))
+            .map(|arg| &arg.value)
+    }
+
+    pub fn text(&self, index: usize, name: &str) -> Option<String> {
+        self.arg(index, name).map(|value| value.to_string())
+    }
+
+    // Text of every positional argument
+    pub fn texts(&self) -> Vec<String> {
+        self.args
+            .iter()
+            .filter(|arg| arg.name.is_none())
+            .map(|arg| arg.value.to_string())
+            .collect()
+    }
+
+    pub fn get<T: FromStr>(&self, index: usize, name: &str) -> Result<Option<T>, String> {
+        match self.arg(index, name) {
+            Some(value) => match value.to_string().parse::<T>() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(format!(
+                    "{} is not a valid {} for {}()",
+                    value, name, self.name
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn required<T: FromStr>(&self, index: usize, name: &str) -> Result<T, String> {
+        self.get(index, name)?
+            .ok_or_else(|| format!("{}() needs a {} argument", self.name, name))
+    }
+}
+
+#[cfg(test)]
+fn spec(name: &str) -> Option<Spec> {
+    match name {
+        "chat" => Some((0, &[])),
+        "file" => Some((4, &["start", "end", "cursor"])),
+        "cmd" => Some((1, &["timeout"])),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_parse_command() {
+    let call = parse(r#"file("./a, b.rs", 1, end=12)"#, spec)
+        .unwrap()
+        .unwrap();
+    assert_eq!(call.name, "file");
+    assert_eq!(call.text(0, "path").as_deref(), Some("./a, b.rs"));
+    assert_eq!(call.get::<usize>(1, "start"), Ok(Some(1)));
+    assert_eq!(call.get::<usize>(2, "end"), Ok(Some(12)));
+    assert_eq!(call.get::<usize>(3, "cursor"), Ok(None));
+
+    let call = parse(r#"cmd("echo \"a)\" -- --nocapture, foo")"#, spec)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        call.args[0].value,
+        Value::Str(String::from("echo \"a)\" -- --nocapture, foo"))
+    );
+    let call = parse("file(./src/main.rs, 0.5, none,)", spec)
+        .unwrap()
+        .unwrap();
+    assert_eq!(call.args[1].value, Value::Float(0.5));
+    assert_eq!(call.args[2].value, Value::Word(String::from("none")));
+    assert!(call.get::<usize>(1, "start").is_err());
+    assert!(call.required::<i32>(3, "cursor").is_err());
+
+    assert_eq!(parse("hello (world)", spec), Ok(None));
+    assert_eq!(parse("print(x)", spec), Ok(None));
+    assert!(parse("chat() ", spec).unwrap().is_some());
+    // Pasted code is left in the query
+    assert_eq!(parse("    cmd(\"rm -rf target\")", spec), Ok(None));
+    assert_eq!(parse("chat(message)", spec), Ok(None));
+    assert_eq!(parse("chat() now", spec), Ok(None));
+}
+
+#[test]
+fn test_parse_errors() {
+    let message = |input: &str| parse(input, spec).unwrap_err().to_string();
+    assert_eq!(
+        message(r#"cmd("cargo test)"#),
+        "this string is never closed at column 5"
+    );
+    assert_eq!(message("cmd(ls"), "missing a closing ) at column 7");
+    assert_eq!(
+        message("cmd(ls ls)"),
+        "expected a , between arguments at column 8"
+    );
+    assert_eq!(
+        message("cmd(ls, timeout=1, timeout=2)"),
+        "timeout is given more than once at column 28"
+    );
+    assert_eq!(
+        message("cmd(ls, wait=1)"),
+        "cmd() has no argument named wait at column 14"
+    );
+    assert_eq!(message(r#"cmd("\q")"#), "unknown escape \\q at column 6");
+    assert_eq!(
+        message("cmd(ls) now"),
+        "unexpected text after cmd() at column 9"
+    );
+    let error = parse("cmd(ls ls)", spec).unwrap_err();
+    assert_eq!(
+        error.show("cmd(ls ls)"),
+        "cmd(ls ls)\n       ^ expected a , between arguments at column 8"
+    );
+}
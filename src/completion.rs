@@ -1,37 +1,70 @@
+use crate::chat::ErrorResponse;
 use crate::err::ApiError;
-use crate::http_client;
-use crate::output::Output;
+use crate::http_client::OpenAiClient;
+use crate::output::{trim_blank_lines, Output};
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
 use serde_json::Result as SerdeResult;
+use std::collections::HashMap;
+use text_colorizer::*;
 
-#[derive(Debug, Deserialize, Serialize)]
+const COMPLETIONS_PATH: &str = "/v1/completions";
+
+// Marks where the completion should be inserted, text after it is sent as the `suffix`
+pub const INSERT_MARKER: &str = "[insert]";
+
+// Based off create completion
+// See API reference here https://platform.openai.com/docs/api-reference/completions/create
+// Options that are not set are left out of the request so the API defaults apply
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct CodeCompletionCreateParams {
     pub model: String,
     pub max_tokens: i32,
     pub temperature: f64,
     pub prompt: Vec<String>,
-    // pub stop: Option<Vec<String>>,
-    // pub suffix: Option<String>,
-    // pub stream: Option<bool>,
-    // pub echo: Option<bool>,
-    // pub n: Option<i32>,
-    // pub top_p: Option<f64>,
-    // pub logprobs: Option<i32>,
-    // pub presence_penalty: Option<f64>,
-    // pub frequency_penalty: Option<f64>,
-    // pub best_of: Option<i32>,
-    // pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<i32>,
+    // Maps token ids to a bias from -100 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Splits a prompt on the first `INSERT_MARKER` into the prompt and the suffix to insert before
+pub fn split_insert(prompt: &str) -> (String, Option<String>) {
+    match prompt.split_once(INSERT_MARKER) {
+        Some((prefix, suffix)) => (prefix.to_string(), Some(suffix.to_string())),
+        None => (prompt.to_string(), None),
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct CodeCompletionResponse {
     id: String,
     object: String,
     created: i64,
     model: String,
     choices: Vec<Choice>,
-    usage: Usage,
+    usage: Option<Usage>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -39,11 +72,56 @@ pub struct Choice {
     text: String,
     index: usize,
     logprobs: Option<Logprobs>,
-    finish_reason: String,
+    finish_reason: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Logprobs {}
+// Log probabilities of each generated token, returned when `logprobs` is set
+// See https://platform.openai.com/docs/api-reference/completions/object
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Logprobs {
+    pub tokens: Vec<String>,
+    // The first token is null when the prompt is echoed
+    pub token_logprobs: Vec<Option<f64>>,
+    // The most likely alternatives at each position, up to `logprobs` of them
+    pub top_logprobs: Option<Vec<Option<HashMap<String, f64>>>>,
+    pub text_offset: Vec<usize>,
+}
+
+impl Logprobs {
+    fn extend(&mut self, other: Logprobs) {
+        self.tokens.extend(other.tokens);
+        self.token_logprobs.extend(other.token_logprobs);
+        if let Some(top_logprobs) = other.top_logprobs {
+            self.top_logprobs
+                .get_or_insert_with(Vec::new)
+                .extend(top_logprobs);
+        }
+        self.text_offset.extend(other.text_offset);
+    }
+
+    // Renders each token with a red that gets stronger the less likely the token was,
+    // tokens the model was confident about are left uncoloured. Without color this is the text.
+    pub fn colorize(&self, color: bool) -> String {
+        if !color {
+            return self.tokens.concat();
+        }
+        let mut output = String::new();
+        for (index, token) in self.tokens.iter().enumerate() {
+            let logprob = self.token_logprobs.get(index).copied().flatten();
+            match logprob.map(f64::exp) {
+                Some(probability) if probability < CONFIDENT_PROBABILITY => {
+                    let intensity = (255.0 * probability / CONFIDENT_PROBABILITY) as u8;
+                    output.push_str(&token.truecolor(255, intensity, intensity).to_string());
+                }
+                _ => output.push_str(token),
+            }
+        }
+        output
+    }
+}
+
+// Tokens at or above this probability are not highlighted
+const CONFIDENT_PROBABILITY: f64 = 0.9;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Usage {
@@ -52,48 +130,270 @@ pub struct Usage {
     total_tokens: usize,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Response {
+    // Streamed events have the same shape as the full response, without usage
+    CodeCompletion(CodeCompletionResponse),
+    Error(ErrorResponse),
+}
+
+impl CodeCompletionResponse {
+    // Merges a streamed chunk into the response, appending text to the choice with the same index
+    fn add_chunk(&mut self, chunk: CodeCompletionResponse) {
+        self.id = chunk.id;
+        self.object = chunk.object;
+        self.created = chunk.created;
+        self.model = chunk.model;
+        for chunk_choice in chunk.choices {
+            match self
+                .choices
+                .iter_mut()
+                .find(|c| c.index == chunk_choice.index)
+            {
+                Some(choice) => {
+                    choice.text.push_str(&chunk_choice.text);
+                    if let Some(logprobs) = chunk_choice.logprobs {
+                        choice
+                            .logprobs
+                            .get_or_insert_with(Logprobs::default)
+                            .extend(logprobs);
+                    }
+                    if chunk_choice.finish_reason.is_some() {
+                        choice.finish_reason = chunk_choice.finish_reason;
+                    }
+                }
+                None => self.choices.push(chunk_choice),
+            }
+        }
+    }
+}
+
+impl CodeCompletionResponse {
+    // Like `get_output` but each token is coloured by how likely the model thought it was.
+    // Choices without logprobs, and every choice when `color` is off, are shown as plain text.
+    pub fn get_logprobs_output(&self, color: bool) -> String {
+        let mut output = String::from("");
+        for choice in self.choices.iter() {
+            match &choice.logprobs {
+                Some(logprobs) => output.push_str(&logprobs.colorize(color)),
+                None => output.push_str(&choice.text),
+            }
+            output.push('\n');
+        }
+        if !color {
+            return output;
+        }
+        output.push_str(&format!(
+            "{}",
+            "Tokens are shown in red when the model was less than 90% sure, the stronger the red the less likely\n"
+                .italic()
+        ));
+        output
+    }
+}
+
 impl Output for CodeCompletionResponse {
     fn get_output(&self) -> String {
         let mut output = String::from("");
         for choice in self.choices.iter() {
-            let lines = choice.text.lines();
-            for line in lines {
-                if line.trim().is_empty() {
-                    continue; // ignore empty or whitespace-only lines
-                }
-                output.push_str(line);
-                output.push_str("\n");
+            let text = trim_blank_lines(&choice.text);
+            if text.is_empty() {
+                continue;
             }
+            output.push_str(text);
+            output.push('\n');
         }
         output
     }
 }
 
-fn parse_completion_response(response: String) -> SerdeResult<CodeCompletionResponse> {
-    match from_str(&response) {
-        Ok(c) => return Ok(c),
-        Err(e) => {
-            return Err(e);
+fn parse_completion_response(response: String) -> SerdeResult<Response> {
+    from_str(&response)
+}
+
+impl OpenAiClient {
+    pub async fn completion(
+        &self,
+        request_defaults: CodeCompletionCreateParams,
+    ) -> Result<CodeCompletionResponse, ApiError> {
+        //TODO: Readd Language
+        let response = self.post(COMPLETIONS_PATH, &request_defaults).await?;
+        match parse_completion_response(response)? {
+            Response::CodeCompletion(r) => Ok(r),
+            Response::Error(e) => Err(ApiError::OpenAi {
+                status: None,
+                error: e.error,
+            }),
         }
-    };
+    }
+
+    // Streams the response, calling `on_delta` with each piece of text as it arrives.
+    pub async fn completion_stream<F: FnMut(&str)>(
+        &self,
+        mut request_defaults: CodeCompletionCreateParams,
+        mut on_delta: F,
+    ) -> Result<CodeCompletionResponse, ApiError> {
+        request_defaults.stream = Some(true);
+        let mut response = CodeCompletionResponse::default();
+        self.post_stream(
+            COMPLETIONS_PATH,
+            &request_defaults,
+            |event| match from_str::<Response>(event)? {
+                Response::CodeCompletion(chunk) => {
+                    for choice in chunk.choices.iter() {
+                        on_delta(&choice.text);
+                    }
+                    response.add_chunk(chunk);
+                    Ok(())
+                }
+                Response::Error(e) => Err(ApiError::OpenAi {
+                    status: None,
+                    error: e.error,
+                }),
+            },
+        )
+        .await?;
+        Ok(response)
+    }
 }
 
-pub async fn process_completion_prompt(
-    request_defaults: CodeCompletionCreateParams,
-) -> Result<CodeCompletionResponse, ApiError> {
-    //TODO: Readd Language
-    let result = http_client::send_completion_request(request_defaults).await;
+#[tokio::test]
+async fn test_completion() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token").with_base_url(&server.url());
+    let request_base = CodeCompletionCreateParams {
+        model: String::from("fake-model"),
+        prompt: vec![String::from("hello!")],
+        max_tokens: 3000,
+        temperature: 0.7,
+        ..Default::default()
+    };
+
+    let mock = server
+        .mock("POST", "/v1/completions")
+        .match_header("authorization", "Bearer test-token")
+        .match_body(mockito::Matcher::PartialJson(
+            serde_json::json!({"prompt": ["hello!"]}),
+        ))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{"id": "1", "object": "text_completion", "created": 1, "model": "fake-model",
+            "choices": [{"text": "\nhello world", "index": 0, "logprobs": null, "finish_reason": "stop"}]}"#,
+        )
+        .create_async()
+        .await;
+    let error = server
+        .mock("POST", "/v1/completions")
+        .match_body(mockito::Matcher::PartialJson(
+            serde_json::json!({"prompt": ["error"]}),
+        ))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"error": {"message": "overloaded", "type": "server_error", "param": null, "code": null}}"#)
+        .create_async()
+        .await;
+
+    let response = client.completion(request_base.clone()).await.unwrap();
+    let result = client
+        .completion(CodeCompletionCreateParams {
+            prompt: vec![String::from("error")],
+            ..request_base
+        })
+        .await;
+
+    mock.assert_async().await;
+    error.assert_async().await;
+    assert_eq!(response.get_output(), "hello world\n");
     match result {
-        Ok(response) => match parse_completion_response(response) {
-            Ok(completion) => {
-                return Ok(completion);
-            }
-            Err(e) => {
-                return Err(ApiError::new(&e.to_string()));
-            }
-        },
-        Err(e) => {
-            return Err(ApiError::new(&e.to_string()));
+        Err(ApiError::OpenAi { status, error }) => {
+            assert_eq!(status, None);
+            assert_eq!(error.message, "overloaded");
         }
+        other => panic!("unexpected result {:?}", other),
     }
 }
+
+#[tokio::test]
+async fn test_completion_stream() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token").with_base_url(&server.url());
+    let request_base = CodeCompletionCreateParams {
+        model: String::from("fake-model"),
+        prompt: vec![String::from("fn main() {")],
+        max_tokens: 3000,
+        temperature: 0.7,
+        ..Default::default()
+    };
+
+    let chunk = |text: &str| {
+        format!(
+            "data: {{\"id\":\"1\",\"object\":\"text_completion\",\"created\":1,\"model\":\"fake-model\",\"choices\":[{{\"text\":\"{}\",\"index\":0,\"logprobs\":null,\"finish_reason\":null}}]}}\n\n",
+            text
+        )
+    };
+    let mock = server
+        .mock("POST", "/v1/completions")
+        .match_body(mockito::Matcher::PartialJson(
+            serde_json::json!({"stream": true}),
+        ))
+        .with_status(200)
+        .with_header("content-type", "text/event-stream")
+        .with_body(format!(
+            "{}{}data: [DONE]\n\n",
+            chunk("\\n    "),
+            chunk("todo!()")
+        ))
+        .create_async()
+        .await;
+
+    let mut deltas = vec![];
+    let response = client
+        .completion_stream(request_base, |delta| deltas.push(delta.to_string()))
+        .await
+        .unwrap();
+
+    mock.assert_async().await;
+    assert_eq!(deltas, vec!["\n    ", "todo!()"]);
+    assert_eq!(response.get_output(), "    todo!()\n");
+}
+
+#[test]
+fn test_logprobs() {
+    let response: Response = from_str(
+        r#"{"id": "1", "object": "text_completion", "created": 1, "model": "fake-model",
+        "choices": [{"text": " world!", "index": 0, "finish_reason": "stop",
+        "logprobs": {"tokens": [" world", "!"], "token_logprobs": [-0.01, -2.3],
+        "top_logprobs": [{" world": -0.01}, {"!": -2.3, ".": -0.2}], "text_offset": [5, 11]}}]}"#,
+    )
+    .unwrap();
+    let Response::CodeCompletion(response) = response else {
+        panic!("expected a completion")
+    };
+    let logprobs = response.choices[0].logprobs.as_ref().unwrap();
+    assert_eq!(logprobs.tokens, vec![" world", "!"]);
+    assert_eq!(logprobs.text_offset, vec![5, 11]);
+    assert_eq!(
+        logprobs.top_logprobs.as_ref().unwrap()[1].as_ref().unwrap()["."],
+        -0.2
+    );
+    assert_eq!(response.get_logprobs_output(false), " world!\n");
+    // Only the token below 90% is coloured, exp(-2.3) is about 10% so it is a strong red
+    // text_colorizer leaves text plain when stdout is not a terminal, as in tests
+    control::set_override(true);
+    let output = response.get_logprobs_output(true);
+    assert!(output.starts_with(" world\x1b[38;2;255;28;28m!\x1b[0m\n"));
+}
+
+#[test]
+fn test_split_insert() {
+    assert_eq!(
+        split_insert("fn add(a: i32, b: i32) -> i32 {\n[insert]\n}\n"),
+        (
+            String::from("fn add(a: i32, b: i32) -> i32 {\n"),
+            Some(String::from("\n}\n"))
+        )
+    );
+    assert_eq!(split_insert("hello"), (String::from("hello"), None));
+}
@@ -0,0 +1,51 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Used when neither $VISUAL nor $EDITOR is set
+const DEFAULT_EDITOR: &str = "vi";
+
+fn editor() -> String {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_EDITOR))
+}
+
+// Opens `text` in the user's editor through a temp file and returns what was saved.
+// The editor is run by the shell so it can include arguments i.e EDITOR="code --wait"
+pub fn edit_text(text: &str) -> io::Result<String> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or_default();
+    let path = env::temp_dir().join(format!("gptshell-{}-{}.md", std::process::id(), nanos));
+    fs::write(&path, text)?;
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor()))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let edited = match status {
+        Ok(status) if status.success() => fs::read_to_string(&path),
+        Ok(status) => Err(io::Error::other(format!(
+            "{} exited with {}",
+            editor(),
+            status
+        ))),
+        Err(e) => Err(e),
+    };
+    _ = fs::remove_file(&path);
+    edited
+}
+
+#[test]
+fn test_edit_text() {
+    env::remove_var("VISUAL");
+    env::set_var("EDITOR", "sed -i s/hello/goodbye/");
+    assert_eq!(edit_text("hello world\n").unwrap(), "goodbye world\n");
+}
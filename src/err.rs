@@ -1,23 +1,81 @@
+use crate::chat;
 use std::error::Error;
 use std::fmt;
 
 #[derive(Debug)]
-pub struct ApiError {
-    message: String,
+pub enum ApiError {
+    // OPENAI_API_KEY is not set
+    MissingCredentials,
+    // The API answered with an error status and a body that is not an OpenAI error object
+    Status {
+        status: u16,
+        body: String,
+    },
+    // The request could not be sent or the response could not be read
+    Transport(reqwest::Error),
+    // The response body did not match the expected json
+    Decode(serde_json::Error),
+    // The API answered with an error object, the status is None when it came in the body of a
+    // successful response or as an event in a stream
+    // See https://platform.openai.com/docs/guides/error-codes/api-errors
+    OpenAi {
+        status: Option<u16>,
+        error: chat::Error,
+    },
 }
 
 impl ApiError {
-    pub fn new(message: &str) -> ApiError {
-        ApiError {
-            message: message.to_string(),
+    // HTTP status of the response, if one was received
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ApiError::Status { status, .. } => Some(*status),
+            ApiError::OpenAi { status, .. } => *status,
+            ApiError::Transport(e) => e.status().map(|status| status.as_u16()),
+            _ => None,
         }
     }
 }
 
 impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.message)
+        match self {
+            ApiError::MissingCredentials => write!(
+                f,
+                "OPENAI_API_KEY environment variable is not set, please set it before continuing"
+            ),
+            ApiError::Status { status, body } => write!(f, "HTTP status {}: {}", status, body),
+            ApiError::Transport(e) => write!(f, "request failed: {}", e),
+            ApiError::Decode(e) => write!(f, "unable to decode response: {}", e),
+            ApiError::OpenAi {
+                status: Some(status),
+                error,
+            } => write!(f, "HTTP status {}: {}", status, error),
+            ApiError::OpenAi {
+                status: None,
+                error,
+            } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for ApiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ApiError::Transport(e) => Some(e),
+            ApiError::Decode(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
-impl Error for ApiError {}
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> ApiError {
+        ApiError::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> ApiError {
+        ApiError::Decode(e)
+    }
+}
@@ -0,0 +1,202 @@
+use crate::command::Spec;
+use gptshell::models::Models;
+use gptshell::session::SessionStore;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper, Result};
+use std::borrow::Cow;
+use text_colorizer::*;
+
+// Commands whose first argument is a path, a model or a session name
+const PATH_COMMANDS: [&str; 10] = [
+    "file", "cat", "files", "tree", "fn", "cd", "import", "export", "save", "apply",
+];
+const MODEL_COMMANDS: [&str; 1] = ["model"];
+const SESSION_COMMANDS: [&str; 2] = ["resume", "session"];
+
+// Completes and hints REPL commands as they are typed
+pub struct ReplHelper {
+    // Command names with their signature i.e file(path, start, end, cursor)
+    commands: Vec<(&'static str, String)>,
+    files: FilenameCompleter,
+    store: Option<SessionStore>,
+}
+
+fn signature(name: &str, (max_args, named): Spec) -> String {
+    let args = match (max_args, named) {
+        (0, _) => String::new(),
+        (_, []) if max_args > 1 => String::from("value, ..."),
+        (_, []) => String::from("value"),
+        (_, named) => named.join(", "),
+    };
+    format!("{}({})", name, args)
+}
+
+impl ReplHelper {
+    pub fn new(commands: &[(&'static str, Spec)], store: Option<SessionStore>) -> ReplHelper {
+        let mut commands: Vec<(&'static str, String)> = commands
+            .iter()
+            .map(|(name, spec)| (*name, signature(name, *spec)))
+            .collect();
+        commands.sort();
+        ReplHelper {
+            commands,
+            files: FilenameCompleter::new(),
+            store,
+        }
+    }
+
+    // Start of the word being completed and the candidates for it
+    fn complete_line(&self, line: &str, pos: usize) -> Result<(usize, Vec<Pair>)> {
+        let typed = &line[..pos];
+        let Some(open) = typed.find('(') else {
+            let start = typed.len() - typed.trim_start().len();
+            let candidates = self
+                .commands
+                .iter()
+                .filter(|(name, _)| name.starts_with(&typed[start..]))
+                .map(|(name, signature)| Pair {
+                    display: signature.clone(),
+                    // Commands without arguments are completed with both brackets
+                    replacement: match signature.ends_with("()") {
+                        true => signature.clone(),
+                        false => format!("{}(", name),
+                    },
+                })
+                .collect();
+            return Ok((start, candidates));
+        };
+        let name = typed[..open].trim();
+        if PATH_COMMANDS.contains(&name) {
+            return self.files.complete_path(line, pos);
+        }
+        let names = if MODEL_COMMANDS.contains(&name) {
+            Models::all()
+                .iter()
+                .map(|model| model.name().to_string())
+                .collect()
+        } else if SESSION_COMMANDS.contains(&name) {
+            match &self.store {
+                Some(store) => store.list().unwrap_or_default(),
+                None => vec![],
+            }
+        } else {
+            vec![]
+        };
+        // Only the first argument is completed, it may have an opening quote
+        let arg = &typed[open + 1..];
+        let quote = arg.len() - arg.trim_start_matches(['"', '\'']).len();
+        if arg.contains(',') || quote > 1 {
+            return Ok((pos, vec![]));
+        }
+        let start = open + 1 + quote;
+        let candidates = names
+            .into_iter()
+            .filter(|name| name.starts_with(&typed[start..]))
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: name,
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+
+    // The rest of the signature of the command being typed, until its arguments are started
+    fn hint_line(&self, line: &str, pos: usize) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        let typed = line.trim_start();
+        if typed.is_empty() {
+            return None;
+        }
+        let (name, bracket) = match typed.strip_suffix('(') {
+            Some(name) => (name, true),
+            None => (typed, false),
+        };
+        let mut matches = self
+            .commands
+            .iter()
+            .filter(|(command, _)| command.starts_with(name));
+        let (_, signature) = match self.commands.iter().find(|(command, _)| *command == name) {
+            Some(command) => command,
+            None if bracket => return None,
+            // Only hint a partly typed name that one command starts with
+            None => match (matches.next(), matches.next()) {
+                (Some(command), None) => command,
+                _ => return None,
+            },
+        };
+        let hint = &signature[typed.len()..];
+        (!hint.is_empty()).then(|| hint.to_string())
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Result<(usize, Vec<Pair>)> {
+        self.complete_line(line, pos)
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        self.hint_line(line, pos)
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(hint.bright_black().to_string())
+    }
+}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[test]
+fn test_complete_and_hint() {
+    let commands: [(&str, Spec); 4] = [
+        ("chat", (0, &[])),
+        ("file", (4, &["path", "start", "end", "cursor"])),
+        ("model", (1, &["model"])),
+        ("models", (0, &[])),
+    ];
+    let helper = ReplHelper::new(&commands, None);
+    let replacements = |line: &str| {
+        let (start, pairs) = helper.complete_line(line, line.len()).unwrap();
+        let replacements: Vec<String> = pairs.into_iter().map(|pair| pair.replacement).collect();
+        (start, replacements)
+    };
+    assert_eq!(replacements("  ch"), (2, vec![String::from("chat()")]));
+    assert_eq!(
+        replacements("mod"),
+        (0, vec![String::from("model("), String::from("models()")])
+    );
+    assert_eq!(
+        replacements("model(\"gpt-4"),
+        (7, vec![String::from("gpt-4"), String::from("gpt-4-32k")])
+    );
+    let (start, pairs) = helper.complete_line("file(\"Cargo.to", 14).unwrap();
+    assert_eq!(start, 6);
+    assert_eq!(pairs[0].replacement, "Cargo.toml");
+
+    assert_eq!(
+        helper.hint_line("fi", 2).as_deref(),
+        Some("le(path, start, end, cursor)")
+    );
+    assert_eq!(
+        helper.hint_line("file(", 5).as_deref(),
+        Some("path, start, end, cursor)")
+    );
+    assert_eq!(helper.hint_line("model", 5).as_deref(), Some("(model)"));
+    assert_eq!(helper.hint_line("mo", 2), None);
+    assert_eq!(helper.hint_line("file(\"a", 7), None);
+    assert_eq!(helper.hint_line("chat()", 6), None);
+}
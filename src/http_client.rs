@@ -1,127 +1,575 @@
-use crate::chat::ChatCreateCompletionParams;
-use crate::completion::CodeCompletionCreateParams;
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use crate::chat::ErrorResponse;
+use crate::err::ApiError;
+use rand::Rng;
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
 use reqwest::Client;
-use reqwest::Result as ReqwestResult;
-use serde_json::json;
-use serde_json::Value;
+use reqwest::RequestBuilder;
+use reqwest::Response;
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::from_str;
 use std::env;
+use std::time::Duration;
 
-//TODO: merge these into one client
-pub async fn send_completion_request(
-    request_base: CodeCompletionCreateParams,
-) -> ReqwestResult<String> {
-    send_completion_base_request("https://api.openai.com", request_base).await
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com";
+const DONE_EVENT: &str = "[DONE]";
+const ORGANIZATION_HEADER: &str = "OpenAI-Organization";
+const RETRY_AFTER_MS_HEADER: &str = "retry-after-ms";
+const RATE_LIMIT_RESET_HEADERS: [&str; 2] =
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"];
+
+// How often and how long to wait before resending a request that was rate limited (429) or
+// failed on the server (5xx). The wait doubles after each attempt, with jitter, unless the
+// response says when to retry through `Retry-After` or the `x-ratelimit-reset-*` headers.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Total number of attempts, including the first request, 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn should_retry(&self, attempt: u32, status: StatusCode, body: &str) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            // Running out of quota is also a 429 but waiting won't fix it
+            return match from_str::<ErrorResponse>(body) {
+                Ok(e) => e.error.code.as_deref() != Some("insufficient_quota"),
+                Err(_) => true,
+            };
+        }
+        status.is_server_error()
+    }
+
+    // Delay before the next attempt, `attempt` is the number of the attempt that just failed
+    fn delay(&self, attempt: u32, headers: &HeaderMap) -> Duration {
+        if let Some(delay) = retry_delay_from_headers(headers) {
+            return delay.min(self.max_backoff);
+        }
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        // Equal jitter, wait at least half of the backoff so retries still spread out
+        let half = backoff / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+fn retry_delay_from_headers(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(millis) = header(RETRY_AFTER_MS_HEADER).and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(seconds_to_duration(millis / 1000.0));
+    }
+    if let Some(seconds) = header(RETRY_AFTER.as_str()).and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(seconds_to_duration(seconds));
+    }
+    RATE_LIMIT_RESET_HEADERS
+        .iter()
+        .filter_map(|name| header(name).and_then(parse_reset_duration))
+        .max()
+}
+
+// Parses the durations used by the `x-ratelimit-reset-*` headers i.e "20ms", "1s", "6m0s" or "1h2m3.5s"
+pub fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+    let mut parsed_unit = false;
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let unit = match (c, chars.peek()) {
+            ('m', Some('s')) => {
+                chars.next();
+                0.001
+            }
+            ('h', _) => 3600.0,
+            ('m', _) => 60.0,
+            ('s', _) => 1.0,
+            _ => return None,
+        };
+        total += number.parse::<f64>().ok()? * unit;
+        number.clear();
+        parsed_unit = true;
+    }
+    if !number.is_empty() || !parsed_unit {
+        return None;
+    }
+    Some(seconds_to_duration(total))
+}
+
+// Headers can hold values too large for a Duration i.e "inf", those are capped by `max_backoff`
+fn seconds_to_duration(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds.max(0.0)).unwrap_or(Duration::MAX)
+}
+
+// Client for the OpenAI API, holds the connection pool so it should be created once and reused.
+// Endpoints are implemented next to their request and response types i.e `OpenAiClient::chat`
+// lives in chat.rs
+#[derive(Debug, Clone)]
+pub struct OpenAiClient {
+    base_url: String,
+    api_key: String,
+    organization: Option<String>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    client: Client,
+}
+
+impl OpenAiClient {
+    pub fn new(api_key: &str) -> OpenAiClient {
+        let connect_timeout = Some(Duration::from_secs(30));
+        OpenAiClient {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            api_key: api_key.to_string(),
+            organization: None,
+            timeout: None,
+            connect_timeout,
+            retry_policy: RetryPolicy::default(),
+            client: build_client(None, connect_timeout),
+        }
+    }
+
+    // Reads the API key from OPENAI_API_KEY, and optionally the organization and base url
+    // from OPENAI_ORGANIZATION and OPENAI_BASE_URL
+    pub fn from_env() -> Result<OpenAiClient, ApiError> {
+        let api_key = env::var("OPENAI_API_KEY").map_err(|_| ApiError::MissingCredentials)?;
+        let mut client = OpenAiClient::new(&api_key);
+        if let Ok(organization) = env::var("OPENAI_ORGANIZATION") {
+            client = client.with_organization(&organization);
+        }
+        if let Ok(base_url) = env::var("OPENAI_BASE_URL") {
+            client = client.with_base_url(&base_url);
+        }
+        Ok(client)
+    }
+
+    // Points the client at another server i.e a proxy or a mock, without the trailing `/v1`
+    pub fn with_base_url(mut self, base_url: &str) -> OpenAiClient {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_organization(mut self, organization: &str) -> OpenAiClient {
+        self.organization = Some(organization.to_string());
+        self
+    }
+
+    // Timeout for the whole request, including reading a streamed response
+    pub fn with_timeout(mut self, timeout: Duration) -> OpenAiClient {
+        self.timeout = Some(timeout);
+        self.client = build_client(self.timeout, self.connect_timeout);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> OpenAiClient {
+        self.connect_timeout = Some(connect_timeout);
+        self.client = build_client(self.timeout, self.connect_timeout);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> OpenAiClient {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> OpenAiClient {
+        self.retry_policy.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn request<T: Serialize + ?Sized>(&self, path: &str, body: &T) -> RequestBuilder {
+        let url = format!("{}{}", self.base_url, path);
+        let mut request_builder = self.client.post(url);
+        let auth_header = format!("Bearer {}", self.api_key);
+        request_builder = request_builder.header(AUTHORIZATION, auth_header);
+        request_builder = request_builder.header(CONTENT_TYPE, "application/json");
+        if let Some(organization) = &self.organization {
+            request_builder = request_builder.header(ORGANIZATION_HEADER, organization);
+        }
+        request_builder.json(body)
+    }
+
+    // Sends the request, retrying rate limited and server errors according to the retry policy,
+    // and turns an error status into an `ApiError`
+    async fn send<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<Response, ApiError> {
+        let mut attempt = 1;
+        let (status, body) = loop {
+            let response = self.request(path, body).send().await?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+            let headers = response.headers().clone();
+            let body = response.text().await?;
+            if !self.retry_policy.should_retry(attempt, status, &body) {
+                break (status, body);
+            }
+            tokio::time::sleep(self.retry_policy.delay(attempt, &headers)).await;
+            attempt += 1;
+        };
+        match from_str::<ErrorResponse>(&body) {
+            Ok(e) => Err(ApiError::OpenAi {
+                status: Some(status.as_u16()),
+                error: e.error,
+            }),
+            Err(_) => Err(ApiError::Status {
+                status: status.as_u16(),
+                body,
+            }),
+        }
+    }
+
+    // Posts `body` as json to `path` i.e "/v1/chat/completions" and returns the response body
+    pub async fn post<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<String, ApiError> {
+        Ok(self.send(path, body).await?.text().await?)
+    }
+
+    // Posts a request with `stream: true` and calls `on_event` with the payload of every
+    // server-sent event as it arrives. If the server answers with a plain body instead of
+    // an event stream the whole body is passed through as one event. Returning an error from
+    // `on_event` stops reading the stream.
+    pub async fn post_stream<T: Serialize + ?Sized, F: FnMut(&str) -> Result<(), ApiError>>(
+        &self,
+        path: &str,
+        body: &T,
+        mut on_event: F,
+    ) -> Result<(), ApiError> {
+        let mut response = self.send(path, body).await?;
+        let is_event_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("text/event-stream"))
+            .unwrap_or(false);
+        if !is_event_stream {
+            return on_event(&response.text().await?);
+        }
+
+        let mut parser = EventStreamParser::new();
+        while let Some(chunk) = response.chunk().await? {
+            for event in parser.feed(&chunk) {
+                if event == DONE_EVENT {
+                    return Ok(());
+                }
+                on_event(&event)?;
+            }
+        }
+        for event in parser.finish() {
+            if event != DONE_EVENT {
+                on_event(&event)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn build_client(timeout: Option<Duration>, connect_timeout: Option<Duration>) -> Client {
+    let mut builder = Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    //TODO: surface this, it only fails if the TLS backend cannot be initialised
+    builder.build().expect("Failed to build HTTP client")
+}
+
+// Incremental parser for `text/event-stream` bodies, only the `data` field is used by the
+// OpenAI API so every other field is ignored.
+// See https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+pub struct EventStreamParser {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl EventStreamParser {
+    pub fn new() -> EventStreamParser {
+        EventStreamParser {
+            buffer: vec![],
+            data: vec![],
+        }
+    }
+
+    // Adds a chunk of the body and returns the data of every event completed by it
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = vec![];
+        while let Some(position) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=position).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if let Some(event) = self.parse_line(line) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    // Flushes an event that was not terminated by a blank line before the body ended
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut events = self.feed(b"\n");
+        if let Some(event) = self.parse_line("") {
+            events.push(event);
+        }
+        events
+    }
+
+    fn parse_line(&mut self, line: &str) -> Option<String> {
+        if line.is_empty() {
+            if self.data.is_empty() {
+                return None;
+            }
+            let event = self.data.join("\n");
+            self.data.clear();
+            return Some(event);
+        }
+        if let Some(value) = line.strip_prefix("data:") {
+            self.data
+                .push(value.strip_prefix(' ').unwrap_or(value).to_string());
+        }
+        None
+    }
 }
 
-pub async fn send_completion_base_request(
-    base_url: &str,
-    request_base: CodeCompletionCreateParams,
-) -> ReqwestResult<String> {
-    let url = format!("{}{}", base_url, "/v1/completions");
-    let request_json = json!({
-        "model": request_base.model,
-        "prompt": request_base.prompt,
-        "temperature": request_base.temperature,
-        "max_tokens": request_base.max_tokens,
-    });
-    send_base_request(&url, request_json).await
+impl Default for EventStreamParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_event_stream_parser() {
+    let mut parser = EventStreamParser::new();
+    assert!(parser.feed(b": keep-alive\n\ndata: {\"a\"").is_empty());
+    assert_eq!(parser.feed(b":1}\r\n\r\ndata: [DO"), vec![r#"{"a":1}"#]);
+    assert_eq!(parser.feed("NE]\n\ndata: é".as_bytes()), vec!["[DONE]"]);
+    assert_eq!(parser.finish(), vec!["é"]);
 }
 
 #[tokio::test]
-async fn test_send_completion_base_request() {
+async fn test_post_sends_credentials() {
     let mut server = mockito::Server::new_async().await;
-    env::set_var("OPENAI_API_KEY", "test-token");
-
-    let url = server.url();
-    let request_base = CodeCompletionCreateParams {
-        model: String::from("fake-model"),
-        prompt: vec![String::from("hello!")],
-        max_tokens: 3000,
-        temperature: 0.7,
-    };
+    let client = OpenAiClient::new("test-token")
+        .with_organization("org-test")
+        .with_base_url(&format!("{}/", server.url()));
 
     let mock = server
-        .mock("POST", "/v1/completions")
+        .mock("POST", "/v1/models")
+        .match_header("authorization", "Bearer test-token")
+        .match_header("openai-organization", "org-test")
+        .match_header("content-type", "application/json")
         .with_status(200)
-        .with_header("content-type", "application/json")
-        .with_header("Authorization", "Bearer test-token")
-        .with_body(r#"{"fake": "TODO: check request"}"#)
+        .with_body("{}")
         .create_async()
         .await;
 
-    _ = send_completion_base_request(&url, request_base).await;
+    let response = client.post("/v1/models", &serde_json::json!({})).await;
 
     mock.assert_async().await;
+    assert_eq!(response.unwrap(), "{}");
 }
 
-//TODO: refactor this to make it easier to do mocking
-pub async fn send_chat_request(request_base: ChatCreateCompletionParams) -> ReqwestResult<String> {
-    send_chat_base_request("https://api.openai.com", request_base).await
-}
+#[tokio::test]
+async fn test_post_stream() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token").with_base_url(&server.url());
+
+    let mock = server
+        .mock("POST", "/v1/chat/completions")
+        .with_status(200)
+        .with_header("content-type", "text/event-stream")
+        .with_body("data: {\"n\":1}\n\ndata: {\"n\":2}\n\ndata: [DONE]\n\n")
+        .create_async()
+        .await;
 
-pub async fn send_chat_base_request(
-    base_url: &str,
-    request_base: ChatCreateCompletionParams,
-) -> ReqwestResult<String> {
-    let url = format!("{}{}", base_url, "/v1/chat/completions");
+    let mut events = vec![];
+    client
+        .post_stream("/v1/chat/completions", &serde_json::json!({}), |event| {
+            events.push(event.to_string());
+            Ok(())
+        })
+        .await
+        .unwrap();
 
-    let request_json = json!({
-        "model": request_base.model,
-        "messages": request_base.messages,
-        "temperature": request_base.temperature,
-        "max_tokens": request_base.max_tokens,
-    });
-    send_base_request(&url, request_json).await
+    mock.assert_async().await;
+    assert_eq!(events, vec![r#"{"n":1}"#, r#"{"n":2}"#]);
 }
 
-pub async fn send_base_request(url: &str, request_json: Value) -> ReqwestResult<String> {
-    let client = Client::new();
-    let api_key = env::var("OPENAI_API_KEY");
-    match api_key.clone() {
-        Ok(api_key) => {
-            let mut request_builder = client.post(url);
-            let auth_header = format!("Bearer {}", api_key);
-            request_builder = request_builder.header(AUTHORIZATION, auth_header);
-            request_builder = request_builder.header(CONTENT_TYPE, "application/json");
-            let response = request_builder
-                .json(&request_json)
-                .send()
-                .await?
-                .text()
-                .await?;
-            return Ok(response);
+#[tokio::test]
+async fn test_post_error_status() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token")
+        .with_base_url(&server.url())
+        .with_max_attempts(1);
+
+    let mock = server
+        .mock("POST", "/v1/chat/completions")
+        .with_status(401)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "param": null, "code": "invalid_api_key"}}"#)
+        .create_async()
+        .await;
+    let bad_gateway = server
+        .mock("POST", "/v1/completions")
+        .with_status(502)
+        .with_body("Bad Gateway")
+        .create_async()
+        .await;
+
+    let unauthorized = client
+        .post("/v1/chat/completions", &serde_json::json!({}))
+        .await;
+    let gateway = client.post("/v1/completions", &serde_json::json!({})).await;
+
+    mock.assert_async().await;
+    bad_gateway.assert_async().await;
+    match unauthorized {
+        Err(ApiError::OpenAi { status, error }) => {
+            assert_eq!(status, Some(401));
+            assert_eq!(error.code.as_deref(), Some("invalid_api_key"));
         }
-        Err(_) => {
-            eprintln!("Error: OPENAI_API_KEY environment variable is not set, please set it before continuing");
-            std::process::exit(1);
+        other => panic!("unexpected result {:?}", other),
+    }
+    match gateway {
+        Err(ApiError::Status { status, body }) => {
+            assert_eq!(status, 502);
+            assert_eq!(body, "Bad Gateway");
         }
+        other => panic!("unexpected result {:?}", other),
     }
 }
 
 #[tokio::test]
-async fn test_send_chat_base_request() {
+async fn test_post_retries_server_errors() {
     let mut server = mockito::Server::new_async().await;
-    env::set_var("OPENAI_API_KEY", "test-token");
+    let client = OpenAiClient::new("test-token")
+        .with_base_url(&server.url())
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+        });
 
-    let url = server.url();
-    let request_base = ChatCreateCompletionParams {
-        model: Some(String::from("fake-model")),
-        messages: Some(vec![]),
-        max_tokens: Some(3000),
-        temperature: Some(0.7),
-    };
-
-    let mock = server
+    let unavailable = server
+        .mock("POST", "/v1/chat/completions")
+        .with_status(503)
+        .with_header("x-ratelimit-reset-requests", "1ms")
+        .expect(2)
+        .create_async()
+        .await;
+    let ok = server
         .mock("POST", "/v1/chat/completions")
         .with_status(200)
-        .with_header("content-type", "application/json")
-        .with_header("Authorization", "Bearer test-token")
-        .with_body(r#"{"fake": "TODO: check request"}"#)
+        .with_body("{}")
+        .expect(1)
         .create_async()
         .await;
 
-    _ = send_chat_base_request(&url, request_base).await;
+    let response = client
+        .post("/v1/chat/completions", &serde_json::json!({}))
+        .await;
 
-    mock.assert_async().await;
+    unavailable.assert_async().await;
+    ok.assert_async().await;
+    assert_eq!(response.unwrap(), "{}");
+}
+
+#[tokio::test]
+async fn test_post_gives_up_after_max_attempts() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token")
+        .with_base_url(&server.url())
+        .with_max_attempts(2);
+
+    let rate_limited = server
+        .mock("POST", "/v1/chat/completions")
+        .with_status(429)
+        .with_header("retry-after", "0")
+        .with_body(r#"{"error": {"message": "Rate limit reached", "type": "requests", "param": null, "code": "rate_limit_exceeded"}}"#)
+        .expect(2)
+        .create_async()
+        .await;
+    let quota = server
+        .mock("POST", "/v1/completions")
+        .with_status(429)
+        .with_body(r#"{"error": {"message": "You exceeded your current quota", "type": "insufficient_quota", "param": null, "code": "insufficient_quota"}}"#)
+        .expect(1)
+        .create_async()
+        .await;
+
+    let response = client
+        .post("/v1/chat/completions", &serde_json::json!({}))
+        .await;
+    let quota_response = client.post("/v1/completions", &serde_json::json!({})).await;
+
+    rate_limited.assert_async().await;
+    quota.assert_async().await;
+    assert_eq!(response.unwrap_err().status(), Some(429));
+    assert_eq!(quota_response.unwrap_err().status(), Some(429));
+}
+
+#[test]
+fn test_parse_reset_duration() {
+    assert_eq!(
+        parse_reset_duration("20ms"),
+        Some(Duration::from_millis(20))
+    );
+    assert_eq!(parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+    assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+    assert_eq!(
+        parse_reset_duration("1h2m3.5s"),
+        Some(Duration::from_millis(3_723_500))
+    );
+    assert_eq!(parse_reset_duration("12"), None);
+    assert_eq!(parse_reset_duration("soon"), None);
+    assert_eq!(
+        parse_reset_duration("99999999999999999999s"),
+        Some(Duration::MAX)
+    );
+
+    let policy = RetryPolicy::default();
+    for (name, value) in [
+        ("retry-after", "inf"),
+        ("retry-after-ms", "1e400"),
+        ("x-ratelimit-reset-requests", "99999999999999999999s"),
+    ] {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        assert_eq!(policy.delay(1, &headers), policy.max_backoff);
+    }
 }
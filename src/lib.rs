@@ -1,7 +1,17 @@
 #[macro_use]
 pub mod completion;
+pub mod branch;
 pub mod chat;
 pub mod err;
 pub mod http_client;
+pub mod loader;
 pub mod models;
 pub mod output;
+pub mod patch;
+pub mod persona;
+pub mod render;
+pub mod session;
+pub mod symbol;
+pub mod tokenizer;
+pub mod transcript;
+pub mod truncation;
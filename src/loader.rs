@@ -0,0 +1,172 @@
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+use std::fs;
+use std::path::Path;
+
+// Like .gitignore, for files that should never be sent to the model i.e lock files or fixtures
+pub const IGNORE_FILE: &str = ".gptignore";
+
+// Only the start of a file is checked for the NUL bytes that mark it as binary
+const BINARY_CHECK_BYTES: usize = 8000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedFile {
+    pub path: String,
+    pub contents: String,
+}
+
+impl LoadedFile {
+    // The file in a fence under its path, the fence is made longer than any inside the file
+    pub fn to_query(&self, line_numbers: bool) -> String {
+        let mut fence = String::from("```");
+        while self.contents.contains(&fence) {
+            fence.push('`');
+        }
+        let language = Path::new(&self.path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let contents = match line_numbers {
+            true => number_lines(&self.contents, 1),
+            false => self.contents.clone(),
+        };
+        format!(
+            "{}:\n{}{}\n{}\n{}\n",
+            self.path,
+            fence,
+            language,
+            contents.trim_end_matches('\n'),
+            fence
+        )
+    }
+}
+
+// Prefixes each line with its number, counting from `first`, so the model can refer to them
+pub fn number_lines(text: &str, first: usize) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| format!("{}: {}\n", first + index, line))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct Loaded {
+    pub files: Vec<LoadedFile>,
+    // Paths that were left out because they are binary
+    pub binaries: Vec<String>,
+}
+
+fn display_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    path.strip_prefix("./").unwrap_or(&path).to_string()
+}
+
+// Walks `root` in path order, skipping hidden files and anything .gitignore or .gptignore exclude
+fn walk<F: Fn(&str) -> bool>(root: &str, include: F) -> Result<Loaded, String> {
+    if !Path::new(root).exists() {
+        return Err(format!("{} does not exist", root));
+    }
+    let mut loaded = Loaded::default();
+    let walker = WalkBuilder::new(root)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walker {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let path = display_path(entry.path());
+        if !include(&path) {
+            continue;
+        }
+        let bytes =
+            fs::read(entry.path()).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let binary = bytes[..bytes.len().min(BINARY_CHECK_BYTES)].contains(&0);
+        match String::from_utf8(bytes) {
+            Ok(contents) if !binary => loaded.files.push(LoadedFile { path, contents }),
+            _ => loaded.binaries.push(path),
+        }
+    }
+    Ok(loaded)
+}
+
+// Files matching a glob i.e src/**/*.rs, where * stays within a directory and ** crosses them
+pub fn glob_files(pattern: &str) -> Result<Loaded, String> {
+    let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| e.to_string())?
+        .compile_matcher();
+    // Only the directories before the first wildcard need to be walked
+    let parts: Vec<&str> = pattern.split('/').collect();
+    let root: Vec<&str> = parts[..parts.len() - 1]
+        .iter()
+        .copied()
+        .take_while(|part| !part.contains(['*', '?', '[', '{']))
+        .collect();
+    let root = match root.join("/") {
+        root if !root.is_empty() => root,
+        _ if pattern.starts_with('/') => String::from("/"),
+        _ => String::from("."),
+    };
+    walk(&root, |path| matcher.is_match(path))
+}
+
+// Every file under `dir`
+pub fn dir_files(dir: &str) -> Result<Loaded, String> {
+    walk(dir, |_| true)
+}
+
+#[test]
+fn test_load_files() {
+    let dir = std::env::temp_dir().join(format!("gptshell-loader-{}", std::process::id()));
+    let dir_path = dir.to_string_lossy().to_string();
+    fs::create_dir_all(dir.join("src/nested")).unwrap();
+    fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+    fs::write(dir.join(IGNORE_FILE), "*.lock\n").unwrap();
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::write(dir.join("target/out.rs"), "fn built() {}").unwrap();
+    fs::write(dir.join("Cargo.lock"), "lock").unwrap();
+    fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+    fs::write(dir.join("src/nested/lib.rs"), "```\n").unwrap();
+    fs::write(dir.join("src/logo.png"), [137, 80, 78, 71, 0, 1]).unwrap();
+
+    let loaded = glob_files(&format!("{}/**/*.rs", dir_path)).unwrap();
+    let paths: Vec<&str> = loaded.files.iter().map(|file| file.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            format!("{}/src/main.rs", dir_path),
+            format!("{}/src/nested/lib.rs", dir_path)
+        ]
+    );
+    let loaded = glob_files(&format!("{}/src/*.rs", dir_path)).unwrap();
+    assert_eq!(loaded.files.len(), 1);
+
+    let loaded = dir_files(&format!("{}/src", dir_path)).unwrap();
+    assert_eq!(loaded.files.len(), 2);
+    assert_eq!(loaded.binaries, vec![format!("{}/src/logo.png", dir_path)]);
+    assert_eq!(
+        loaded.files[1].to_query(false),
+        format!("{}/src/nested/lib.rs:\n````rs\n```\n````\n", dir_path)
+    );
+    assert_eq!(
+        loaded.files[0].to_query(true),
+        format!("{}/src/main.rs:\n```rs\n1: fn main() {{}}\n```\n", dir_path)
+    );
+
+    let loaded = dir_files(&dir_path).unwrap();
+    assert!(!loaded
+        .files
+        .iter()
+        .any(|file| file.path.ends_with(".lock") || file.path.contains("target")));
+
+    assert!(dir_files(&format!("{}/missing", dir_path)).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
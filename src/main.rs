@@ -1,13 +1,15 @@
-mod chat;
 mod cli;
-mod completion;
-mod err;
-mod http_client;
-mod models;
-mod output;
+mod command;
+mod editor;
+mod helper;
 mod repl;
+mod settings;
 use crate::cli::Defaults;
-use crate::output::Output;
+use gptshell::http_client::OpenAiClient;
+use gptshell::models::get_model;
+use gptshell::output::Output;
+use gptshell::render::color_enabled;
+use gptshell::tokenizer::{check_context, Encoding};
 use text_colorizer::*;
 
 #[tokio::main]
@@ -18,15 +20,33 @@ async fn main() {
             matches: completion_matches.clone(),
         };
         let output_path = request_defaults.get_output_path();
-        let output =
-            completion::process_completion_prompt(request_defaults.get_request_base()).await;
+        let client = match OpenAiClient::from_env() {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("{}: {}", "Error".red(), e);
+                std::process::exit(1);
+            }
+        };
+        let request = request_defaults.get_request_base();
+        let model = get_model(&request.model);
+        let prompt_tokens = Encoding::for_model(&model).count(&request.prompt.join(""));
+        if let Err(e) = check_context(&model, prompt_tokens, request.max_tokens) {
+            eprintln!("{}: {}", "Error".red(), e);
+            std::process::exit(1);
+        }
+        let show_logprobs = request.logprobs.is_some() && output_path.is_empty();
+        let output = client.completion(request).await;
         match output {
+            Ok(output) if show_logprobs => {
+                println!("{}", output.get_logprobs_output(color_enabled()))
+            }
             Ok(output) => output.parse(output_path),
             Err(e) => {
-                eprintln!("{}: {:?}", "Error".red(), e)
+                eprintln!("{}: {}", "Error".red(), e)
             }
         }
     } else {
-        repl::run_repl().await;
+        let session = matches.get_one::<String>("session").cloned();
+        repl::run_repl(session).await;
     }
 }
@@ -1,5 +1,4 @@
 #[allow(dead_code)]
-
 pub enum Models {
     Gpt4,
     Gpt432k,
@@ -60,7 +59,7 @@ impl Models {
         }
     }
 
-    #[allow(dead_code)]
+    // Size of the context window, shared by the prompt and the response
     pub fn max_tokens(&self) -> i32 {
         match *self {
             Models::Gpt4 => 8192,
@@ -1,5 +1,14 @@
 use text_colorizer::*;
 
+// Drops the blank lines a response often starts or ends with, keeping the ones inside it
+pub fn trim_blank_lines(text: &str) -> &str {
+    let Some(first) = text.find(|c: char| !c.is_whitespace()) else {
+        return "";
+    };
+    let start = text[..first].rfind('\n').map_or(0, |newline| newline + 1);
+    text[start..].trim_end()
+}
+
 pub trait Output {
     fn get_output(&self) -> String;
 
@@ -12,7 +21,7 @@ pub trait Output {
     }
 
     fn parse(&self, output_path: String) {
-        if output_path != "" {
+        if !output_path.is_empty() {
             self.to_file(output_path)
         } else {
             self.to_cli()
@@ -0,0 +1,309 @@
+// Parses unified diffs and applies them to files, allowing for the line numbers and whitespace a
+// model gets wrong. Hunks are found by their context rather than trusted line numbers, and if
+// that fails up to `MAX_FUZZ` context lines at each end of a hunk are ignored, like patch(1).
+
+const MAX_FUZZ: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    // Line the hunk starts at in the original file, 0 when the diff left it out
+    pub old_start: usize,
+    pub lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilePatch {
+    // None for /dev/null, when the file is created or deleted
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+fn parse_path(header: &str) -> Option<String> {
+    // Headers can end with a tab and a timestamp
+    let path = header.split('\t').next().unwrap_or("").trim();
+    (path != "/dev/null").then(|| path.to_string())
+}
+
+// Takes the start line from the old side of a hunk header i.e @@ -12,7 +12,8 @@
+fn parse_hunk_header(line: &str) -> Result<usize, String> {
+    let ranges = line.trim_start_matches('@').trim_start();
+    match ranges.strip_prefix('-') {
+        Some(range) => {
+            let start = range
+                .split([',', ' '])
+                .next()
+                .and_then(|start| start.parse::<usize>().ok());
+            start.ok_or_else(|| format!("{:?} is not a valid hunk header", line))
+        }
+        None => Ok(0),
+    }
+}
+
+fn finish_hunk(hunk: Option<Hunk>, file: &mut FilePatch) -> Result<(), String> {
+    if let Some(hunk) = hunk {
+        if !hunk
+            .lines
+            .iter()
+            .any(|line| !matches!(line, Line::Context(_)))
+        {
+            return Err(format!("a hunk for {} changes nothing", file.path()));
+        }
+        file.hunks.push(hunk);
+    }
+    Ok(())
+}
+
+pub fn parse_patch(text: &str) -> Result<Vec<FilePatch>, String> {
+    let mut files: Vec<FilePatch> = vec![];
+    let mut hunk: Option<Hunk> = None;
+    // Blank lines are usually context with the leading space dropped, unless the hunk ends there
+    let mut blank_lines = 0;
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        if let (Some(old), Some(new)) = (
+            line.strip_prefix("--- "),
+            lines.peek().and_then(|next| next.strip_prefix("+++ ")),
+        ) {
+            if let Some(file) = files.last_mut() {
+                finish_hunk(hunk.take(), file)?;
+            }
+            let (mut old_path, mut new_path) = (parse_path(old), parse_path(new));
+            lines.next();
+            // Strip the a/ and b/ prefixes git adds
+            let prefixed = |path: &Option<String>, prefix: &str| {
+                path.as_ref().is_none_or(|path| path.starts_with(prefix))
+            };
+            if prefixed(&old_path, "a/") && prefixed(&new_path, "b/") {
+                old_path = old_path.map(|path| path[2..].to_string());
+                new_path = new_path.map(|path| path[2..].to_string());
+            }
+            if old_path.is_none() && new_path.is_none() {
+                return Err(String::from("a file is diffed from /dev/null to /dev/null"));
+            }
+            files.push(FilePatch {
+                old_path,
+                new_path,
+                hunks: vec![],
+            });
+            blank_lines = 0;
+            continue;
+        }
+        let Some(file) = files.last_mut() else {
+            continue;
+        };
+        if line.starts_with("@@") {
+            finish_hunk(hunk.take(), file)?;
+            hunk = Some(Hunk {
+                old_start: parse_hunk_header(line)?,
+                lines: vec![],
+            });
+            blank_lines = 0;
+            continue;
+        }
+        let Some(current) = &mut hunk else {
+            continue;
+        };
+        let parsed = match line.chars().next() {
+            None => {
+                blank_lines += 1;
+                continue;
+            }
+            Some(' ') => Line::Context(line[1..].to_string()),
+            Some('-') => Line::Remove(line[1..].to_string()),
+            Some('+') => Line::Add(line[1..].to_string()),
+            Some('\\') => continue,
+            // Anything else ends the hunk, i.e text after the diff
+            _ => {
+                finish_hunk(hunk.take(), file)?;
+                blank_lines = 0;
+                continue;
+            }
+        };
+        for _ in 0..blank_lines {
+            current.lines.push(Line::Context(String::new()));
+        }
+        blank_lines = 0;
+        current.lines.push(parsed);
+    }
+    if let Some(file) = files.last_mut() {
+        finish_hunk(hunk, file)?;
+    }
+    if files.is_empty() {
+        return Err(String::from(
+            "there is no unified diff, it needs --- and +++ file headers",
+        ));
+    }
+    if let Some(file) = files.iter().find(|file| file.hunks.is_empty()) {
+        return Err(format!("there are no hunks for {}", file.path()));
+    }
+    Ok(files)
+}
+
+// Ways lines are compared, from strictest to loosest
+const MATCHERS: [fn(&str, &str) -> bool; 3] = [
+    |a, b| a == b,
+    |a, b| a.trim_end() == b.trim_end(),
+    |a, b| a.split_whitespace().eq(b.split_whitespace()),
+];
+
+// Where `old` is in `lines` from `from` on, closest to `expected` first
+fn find(lines: &[&str], old: &[&str], from: usize, expected: usize) -> Option<usize> {
+    if old.len() > lines.len() {
+        return None;
+    }
+    let mut positions: Vec<usize> = (from..=lines.len() - old.len()).collect();
+    positions.sort_by_key(|position| position.abs_diff(expected));
+    MATCHERS.iter().find_map(|matches| {
+        positions.iter().copied().find(|position| {
+            old.iter()
+                .zip(&lines[*position..])
+                .all(|(old, line)| matches(old, line))
+        })
+    })
+}
+
+impl FilePatch {
+    // The file the patch writes, or deletes
+    pub fn path(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or("")
+    }
+
+    // The patched text, or None when the patch deletes the file
+    pub fn apply(&self, original: &str) -> Result<Option<String>, String> {
+        if self.new_path.is_none() {
+            return Ok(None);
+        }
+        let lines: Vec<&str> = original.lines().collect();
+        let mut output: Vec<String> = vec![];
+        // Lines before this one have been copied or patched
+        let mut next = 0;
+        for (number, hunk) in self.hunks.iter().enumerate() {
+            let leading = hunk
+                .lines
+                .iter()
+                .take_while(|line| matches!(line, Line::Context(_)))
+                .count();
+            let trailing = hunk
+                .lines
+                .iter()
+                .rev()
+                .take_while(|line| matches!(line, Line::Context(_)))
+                .count();
+            let found = (0..=MAX_FUZZ).find_map(|fuzz| {
+                let hunk_lines =
+                    &hunk.lines[fuzz.min(leading)..hunk.lines.len() - fuzz.min(trailing)];
+                let old: Vec<&str> = hunk_lines
+                    .iter()
+                    .filter_map(|line| match line {
+                        Line::Context(text) | Line::Remove(text) => Some(text.as_str()),
+                        Line::Add(_) => None,
+                    })
+                    .collect();
+                let expected = (hunk.old_start + fuzz.min(leading)).saturating_sub(1);
+                let position = match old.is_empty() {
+                    // Only additions, which go where the header says
+                    true => Some(expected.clamp(next, lines.len())),
+                    false => find(&lines, &old, next, expected),
+                };
+                position.map(|position| (position, hunk_lines))
+            });
+            let Some((position, hunk_lines)) = found else {
+                return Err(format!(
+                    "hunk {} does not match the contents of {}",
+                    number + 1,
+                    self.path()
+                ));
+            };
+            output.extend(lines[next..position].iter().map(|line| line.to_string()));
+            next = position;
+            for line in hunk_lines {
+                match line {
+                    // The file's own line is kept in case it only matched loosely
+                    Line::Context(_) => {
+                        output.push(lines[next].to_string());
+                        next += 1;
+                    }
+                    Line::Remove(_) => next += 1,
+                    Line::Add(text) => output.push(text.clone()),
+                }
+            }
+        }
+        output.extend(lines[next..].iter().map(|line| line.to_string()));
+        let mut patched = output.join("\n");
+        if !patched.is_empty() && (original.is_empty() || original.ends_with('\n')) {
+            patched.push('\n');
+        }
+        Ok(Some(patched))
+    }
+}
+
+#[test]
+fn test_parse_patch() {
+    let diff = "Here is the fix:\n\n```diff\ndiff --git a/src/main.rs b/src/main.rs\n--- a/src/main.rs\n+++ b/src/main.rs\t2023-04-01\n@@ -1,3 +1,3 @@\n fn main() {\n-    println!(\"hello\");\n+    println!(\"goodbye\");\n\n }\n```\n\n--- /dev/null\n+++ b/notes.md\n@@ -0,0 +1 @@\n+# Notes\n";
+    let files = parse_patch(diff).unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].old_path.as_deref(), Some("src/main.rs"));
+    assert_eq!(files[0].path(), "src/main.rs");
+    assert_eq!(files[0].hunks[0].old_start, 1);
+    assert_eq!(
+        files[0].hunks[0].lines,
+        vec![
+            Line::Context(String::from("fn main() {")),
+            Line::Remove(String::from("    println!(\"hello\");")),
+            Line::Add(String::from("    println!(\"goodbye\");")),
+            Line::Context(String::new()),
+            Line::Context(String::from("}")),
+        ]
+    );
+    assert_eq!(files[1].old_path, None);
+    assert_eq!(files[1].path(), "notes.md");
+
+    assert!(parse_patch("no diff here").is_err());
+    assert!(parse_patch("--- a/x\n+++ b/x\n@@ -1 +1 @@\n same\n").is_err());
+}
+
+#[test]
+fn test_apply_patch() {
+    let original = "use std::fs;\n\nfn main() {\n    let a = 1;\n    let b = 2;\n    println!(\"{}\", a + b);\n}\n";
+    // The line numbers are wrong, the indentation differs and the last context line is wrong
+    let files = parse_patch(
+        "--- a/main.rs\n+++ b/main.rs\n@@ -10,4 +10,4 @@\n fn main() {\n   let a = 1;\n-    let b = 2;\n+    let b = 3;\n     println!(\"{}\", a * b);\n",
+    )
+    .unwrap();
+    assert_eq!(
+        files[0].apply(original).unwrap().unwrap(),
+        original.replace("b = 2", "b = 3")
+    );
+
+    let files =
+        parse_patch("--- a/main.rs\n+++ b/main.rs\n@@ -1 +1 @@\n-use std::io;\n+use std::env;\n")
+            .unwrap();
+    assert_eq!(
+        files[0].apply(original),
+        Err(String::from(
+            "hunk 1 does not match the contents of main.rs"
+        ))
+    );
+
+    let files =
+        parse_patch("--- /dev/null\n+++ b/new.rs\n@@ -0,0 +1,2 @@\n+fn a() {}\n+fn b() {}\n")
+            .unwrap();
+    assert_eq!(
+        files[0].apply("").unwrap().unwrap(),
+        "fn a() {}\nfn b() {}\n"
+    );
+
+    let files = parse_patch("--- a/old.rs\n+++ /dev/null\n@@ -1 +0,0 @@\n-fn a() {}\n").unwrap();
+    assert_eq!(files[0].apply("fn a() {}\n"), Ok(None));
+}
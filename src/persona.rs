@@ -0,0 +1,68 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Overrides the directory personas are loaded from
+pub const PERSONA_DIR_ENV: &str = "GPTSHELL_PERSONA_DIR";
+
+// A persona is a file in this directory, its name is the file name without the extension
+// and its contents are used as the system message i.e personas/rust-reviewer.md
+pub fn persona_dir() -> Option<PathBuf> {
+    match env::var(PERSONA_DIR_ENV) {
+        Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => dirs::config_dir().map(|dir| dir.join("gptshell").join("personas")),
+    }
+}
+
+fn persona_name(path: &Path) -> Option<String> {
+    if !path.is_file() {
+        return None;
+    }
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+}
+
+// Names of the personas in `dir`, sorted
+pub fn list_personas(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        if let Some(name) = persona_name(&entry?.path()) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn load_persona(dir: &Path, name: &str) -> io::Result<String> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if persona_name(&path).as_deref() == Some(name) {
+            return Ok(fs::read_to_string(path)?.trim().to_string());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no persona named {} in {}", name, dir.display()),
+    ))
+}
+
+#[test]
+fn test_load_persona() {
+    let dir = env::temp_dir().join(format!("gptshell-personas-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("rust-reviewer.md"), "You review Rust code.\n").unwrap();
+    fs::write(dir.join("pirate.txt"), "Talk like a pirate.").unwrap();
+
+    assert_eq!(
+        list_personas(&dir).unwrap(),
+        vec!["pirate", "rust-reviewer"]
+    );
+    assert_eq!(
+        load_persona(&dir, "rust-reviewer").unwrap(),
+        "You review Rust code."
+    );
+    assert!(load_persona(&dir, "missing").is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,250 @@
+use std::env;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+
+const THEME: &str = "base16-ocean.dark";
+
+const BOLD: &str = "1";
+const DIM: &str = "2";
+const ITALIC: &str = "3";
+const UNDERLINE: &str = "1;4";
+const CODE: &str = "33";
+const MARKER: &str = "36";
+
+// Loading the syntaxes takes a moment, so it is only done for the first code block
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    &THEMES.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
+
+// Replies are only styled for a terminal, and never when NO_COLOR is set (https://no-color.org)
+pub fn color_enabled() -> bool {
+    let no_color = env::var_os("NO_COLOR").map(|value| !value.is_empty());
+    std::io::stdout().is_terminal() && no_color != Some(true)
+}
+
+fn paint(code: &str, text: &str) -> String {
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+// Styles `code`, **bold** and [links](url) within a line
+fn render_inline(line: &str) -> String {
+    let mut output = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(['`', '*', '[']) {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (styled, length) = if let Some(code) = rest.strip_prefix('`') {
+            match code.find('`') {
+                Some(end) => (paint(CODE, &code[..end]), end + 2),
+                None => (String::from("`"), 1),
+            }
+        } else if let Some(bold) = rest.strip_prefix("**") {
+            match bold.find("**") {
+                Some(end) if end > 0 => (paint(BOLD, &bold[..end]), end + 4),
+                _ => (String::from("**"), 2),
+            }
+        } else if let Some(link) = rest.strip_prefix('[') {
+            let target = link
+                .find("](")
+                .and_then(|text| Some((text, text + 2 + link[text + 2..].find(')')?)));
+            match target {
+                Some((text, end)) => (
+                    format!(
+                        "{} {}",
+                        paint(UNDERLINE, &link[..text]),
+                        paint(DIM, &format!("({})", &link[text + 2..end]))
+                    ),
+                    end + 2,
+                ),
+                None => (String::from("["), 1),
+            }
+        } else {
+            (rest[..1].to_string(), 1)
+        };
+        output.push_str(&styled);
+        rest = &rest[length..];
+    }
+    output.push_str(rest);
+    output
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    match line[level..].strip_prefix(' ') {
+        Some(text) if (1..=6).contains(&level) => Some((level, text.trim())),
+        _ => None,
+    }
+}
+
+// The marker of a list item and the text after it i.e ("-", "item") or ("1.", "item")
+fn list_item(line: &str) -> Option<(&str, &str)> {
+    let (marker, text) = line.split_once(' ')?;
+    let ordered = marker.len() > 1
+        && marker.ends_with(['.', ')'])
+        && marker[..marker.len() - 1]
+            .chars()
+            .all(|c| c.is_ascii_digit());
+    (matches!(marker, "-" | "*" | "+") || ordered).then_some((marker, text))
+}
+
+fn is_rule(line: &str) -> bool {
+    let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    line.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|rule| line.chars().all(|c| c == *rule))
+}
+
+struct Fence {
+    marker: String,
+    highlighter: HighlightLines<'static>,
+}
+
+// Renders markdown for the terminal a line at a time, so a streamed reply can be rendered as it
+// arrives. Without color the text is passed through unchanged.
+pub struct MarkdownRenderer {
+    color: bool,
+    // Text after the last complete line
+    pending: String,
+    fence: Option<Fence>,
+}
+
+impl MarkdownRenderer {
+    pub fn new(color: bool) -> MarkdownRenderer {
+        MarkdownRenderer {
+            color,
+            pending: String::new(),
+            fence: None,
+        }
+    }
+
+    // Adds `text` and returns the lines it completed, rendered
+    pub fn push(&mut self, text: &str) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        self.pending.push_str(text);
+        let Some(end) = self.pending.rfind('\n') else {
+            return String::new();
+        };
+        let lines: String = self.pending.drain(..=end).collect();
+        lines.lines().map(|line| self.render_line(line)).collect()
+    }
+
+    // Renders whatever is left after the last line break
+    pub fn finish(&mut self) -> String {
+        if self.pending.is_empty() {
+            return String::new();
+        }
+        let line = std::mem::take(&mut self.pending);
+        let rendered = self.render_line(&line);
+        rendered.trim_end_matches('\n').to_string()
+    }
+
+    fn render_line(&mut self, line: &str) -> String {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        if let Some(fence) = &mut self.fence {
+            if trimmed.trim_end() == fence.marker {
+                self.fence = None;
+                return format!("{}\n", paint(DIM, line));
+            }
+            let highlighted = fence
+                .highlighter
+                .highlight_line(&format!("{}\n", line), syntaxes())
+                .map(|ranges| as_24_bit_terminal_escaped(&ranges, false))
+                .unwrap_or_else(|_| format!("{}\n", line));
+            if line.is_empty() {
+                return String::from("\n");
+            }
+            return format!("{}\x1b[0m\n", highlighted.trim_end_matches('\n'));
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            let marker: String = trimmed
+                .chars()
+                .take_while(|c| *c == trimmed.as_bytes()[0] as char)
+                .collect();
+            let language = trimmed[marker.len()..].split_whitespace().next();
+            let syntax = language
+                .and_then(|language| syntaxes().find_syntax_by_token(language))
+                .unwrap_or_else(|| syntaxes().find_syntax_plain_text());
+            self.fence = Some(Fence {
+                marker,
+                highlighter: HighlightLines::new(syntax, theme()),
+            });
+            return format!("{}\n", paint(DIM, line));
+        }
+        let rendered = if let Some((level, text)) = heading(trimmed) {
+            paint(if level == 1 { UNDERLINE } else { BOLD }, text)
+        } else if is_rule(trimmed) {
+            paint(DIM, &"─".repeat(40))
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            format!(
+                "{}{}",
+                paint(DIM, "│ "),
+                paint(ITALIC, &render_inline(quote.trim_start()))
+            )
+        } else if let Some((marker, text)) = list_item(trimmed) {
+            let marker = match marker {
+                "-" | "*" | "+" => "•",
+                marker => marker,
+            };
+            format!("{} {}", paint(MARKER, marker), render_inline(text))
+        } else {
+            render_inline(trimmed)
+        };
+        format!("{}{}\n", indent, rendered)
+    }
+}
+
+// Renders a whole reply, keeping its blank lines
+pub fn render_markdown(text: &str, color: bool) -> String {
+    let mut renderer = MarkdownRenderer::new(color);
+    let mut output = renderer.push(text);
+    output.push_str(&renderer.finish());
+    output
+}
+
+#[test]
+fn test_render_markdown() {
+    let reply = "# Title\n\nSome `code` and **bold**, see [docs](https://docs.rs).\n\n- one\n2. two\n\n```rust\nfn main() {}\n\n```\n> quoted\n---";
+    assert_eq!(render_markdown(reply, false), reply);
+
+    let rendered = render_markdown(reply, true);
+    let lines: Vec<&str> = rendered.lines().collect();
+    assert_eq!(lines.len(), reply.lines().count());
+    assert_eq!(lines[0], "\x1b[1;4mTitle\x1b[0m");
+    assert_eq!(lines[1], "");
+    assert_eq!(
+        lines[2],
+        "Some \x1b[33mcode\x1b[0m and \x1b[1mbold\x1b[0m, see \x1b[1;4mdocs\x1b[0m \x1b[2m(https://docs.rs)\x1b[0m."
+    );
+    assert_eq!(lines[4], "\x1b[36m•\x1b[0m one");
+    assert_eq!(lines[5], "\x1b[36m2.\x1b[0m two");
+    assert_eq!(lines[7], "\x1b[2m```rust\x1b[0m");
+    assert!(lines[8].contains("\x1b[38;2;"));
+    assert!(lines[8].contains("main"));
+    assert_eq!(lines[9], "");
+    assert_eq!(lines[10], "\x1b[2m```\x1b[0m");
+    assert_eq!(lines[11], "\x1b[2m│ \x1b[0m\x1b[3mquoted\x1b[0m");
+    assert!(!rendered.ends_with('\n'));
+
+    // Streamed replies render the same as whole ones
+    let mut renderer = MarkdownRenderer::new(true);
+    let mut streamed: String = reply
+        .split_inclusive(['\n', ' '])
+        .map(|delta| renderer.push(delta))
+        .collect();
+    streamed.push_str(&renderer.finish());
+    assert_eq!(streamed, rendered);
+}
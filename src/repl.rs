@@ -1,119 +1,323 @@
-use crate::chat;
-use crate::chat::History;
-use crate::chat::Message;
-use crate::chat::MessageHistory;
-use crate::completion;
-use crate::models;
-use crate::models::{get_model, Models};
-use crate::output::Output;
-use regex::{Captures, Regex};
+use crate::command;
+use crate::command::{Call, Spec, Value};
+use crate::editor::edit_text;
+use crate::helper::ReplHelper;
+use crate::settings::{Settings, OPTIONS};
+use chrono::{DateTime, Local};
+use gptshell::branch::Branches;
+use gptshell::chat;
+use gptshell::chat::History;
+use gptshell::chat::Message;
+use gptshell::chat::MessageHistory;
+use gptshell::completion::INSERT_MARKER;
+use gptshell::http_client::OpenAiClient;
+use gptshell::loader::{dir_files, glob_files, number_lines, LoadedFile, IGNORE_FILE};
+use gptshell::models::{get_model, Models};
+use gptshell::output::Output;
+use gptshell::patch::{parse_patch, FilePatch};
+use gptshell::persona::{list_personas, load_persona, persona_dir, PERSONA_DIR_ENV};
+use gptshell::render::{color_enabled, MarkdownRenderer};
+use gptshell::session::{Session, SessionStore, SESSION_DIR_ENV};
+use gptshell::symbol::find_item;
+use gptshell::tokenizer::{check_context, Encoding};
+use gptshell::transcript::{Format, Transcript};
+use gptshell::truncation::{truncate, TruncationPolicy};
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag};
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use similar::TextDiff;
 use spinoff::Streams;
 use spinoff::{spinners, Color, Spinner};
 use std::env;
 use std::fs;
-use std::process::Command;
+use std::io;
+use std::io::{BufRead, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 use text_colorizer::*;
 
 //TODO: make this into an enum and loop through
 fn print_quickstart() {
     println!("{}", "Quickstart:".bold());
+    println!("{} for information on commands", "\"help()\"".italic());
+    println!("{} to print current query", "\"print()\"".italic());
     println!(
-        "{}{}",
-        "\"help()\"".italic(),
-        " for information on commands"
+        "{} to send the query to the chat API",
+        "\"chat()\"".italic()
     );
-    println!("{}{}", "\"print()\"".italic(), " to print current query");
     println!(
-        "{}{}",
-        "\"chat()\"".italic(),
-        " to send the query to the chat API"
+        "{} to send query to completion API",
+        "\"completion()\"".italic()
     );
-    println!(
-        "{}{}",
-        "\"completion()\"".italic(),
-        " to send query to completion API"
-    );
-    println!("{}{}", "\"exit()\"".italic(), " to exit the terminal");
+    println!("{} to exit the terminal", "\"exit()\"".italic());
 }
 
 //TODO: make this into an enum and loop through
 fn print_help() {
+    println!(
+        "Commands take arguments like function calls, text with spaces, commas or brackets goes in quotes i.e {} and arguments can be given by name i.e {}",
+        "cmd(\"ls -la, src\")".blue(),
+        "file(\"./main.rs\", end=12)".blue()
+    );
+    println!(
+        "Press {} to complete command names, file paths, models and sessions, the arguments of the command being typed are hinted in grey",
+        "Tab".blue()
+    );
+    println!();
     println!("{}", "Generic Commands".bold());
     println!(
-        "{}{}",
-        "\"print()\"".blue(),
-        " to print the current terminal query "
+        "{} to print the current terminal query ",
+        "\"print()\"".blue()
+    );
+    println!(
+        "{} to clear the current terminal query and start again",
+        "\"empty()\"".blue()
+    );
+    println!(
+        "{} to open the current terminal query in $VISUAL or $EDITOR and use what is saved",
+        "\"edit()\"".blue()
+    );
+    println!(
+        "{} on a line of its own to start typing a multi-line query, lines are added as typed without running commands until the next {}",
+        MULTILINE_DELIMITER.blue(),
+        MULTILINE_DELIMITER.blue()
+    );
+    println!(
+        "{} to load a file into the query i.e \"file(\"./main.rs\",1,12)\" - the line numbers are optional",
+        "\"cat(\"./path/to/file\",line,line)\"".blue()
     );
     println!(
-        "{}{}",
-        "\"empty()\"".blue(),
-        " to clear the current terminal query and start again"
+        "{} to load a file into the query i.e \"file(\"./main.rs\",1,12)\" - the line numbers are optional",
+        "\"file(\"./path/to/file\",line,line)\"".blue()
     );
     println!(
-        "{}{}",
-        "\"cat(\"./path/to/file\",line,line)\"".blue()," to load a file into the query i.e \"file(\"./main.rs\",1,12)\" - the line numbers are optional"
+        "{} to load a file with an insert marker before line 12, complete() fills in the text at the marker, the marker can also be typed as {}",
+        "\"file(\"./path/to/file\",1,20,12)\"".blue(),
+        INSERT_MARKER
     );
     println!(
-        "{}{}",
-        "\"file(\"./path/to/file\",line,line)\"".blue()," to load a file into the query i.e \"file(\"./main.rs\",1,12)\" - the line numbers are optional"
+        "{} to load every file matching a glob into the query, each under its path, files ignored by .gitignore or {} and binary files are skipped",
+        "\"files(\"src/**/*.rs\")\"".blue(),
+        IGNORE_FILE
     );
     println!(
-        "{}{}",
-        "\"cmd(\"cargo test\")\"".blue()," runs a cmd, currently only in the current working dir, saves output to history so can be included in query"
+        "{} to load just a fn, struct, enum, trait, impl or mod from a Rust file, methods are named after their type i.e {}",
+        "\"fn(\"./src/repl.rs\", \"run_repl\")\"".blue(),
+        "\"Settings::print\"".blue()
     );
-    println!("");
+    println!(
+        "{} to show the files under a directory as a tree and load them into the query in the same way",
+        "\"tree(\"./src\")\"".blue()
+    );
+    println!(
+        "{} runs a command in the shell showing its output as it arrives, the output and exit status are added to the query",
+        "\"cmd(\"cargo test\")\"".blue()
+    );
+    println!(
+        "{} runs it in another directory, killing it after a timeout in seconds, with extra environment variables",
+        "\"cmd(\"cargo test\", cwd=\"./core\", timeout=60, env=\"RUST_LOG=debug RUST_BACKTRACE=1\")\"".blue()
+    );
+    println!(
+        "{} to change the working directory, pwd() shows it",
+        "\"cd(\"./src\")\"".blue()
+    );
+    println!();
     println!("{}", "Completion API Commands".bold());
     println!(
-        "{}{}{}",
-        "\"complete()\"".yellow()," to send the current terminal query to OpenAI Completion API https://platform.openai.com/docs/guides/code",
-        " * note: you must set model to a code model for complete to work i.e model(\"code-cushman-001\")"
+        "{} to send the current terminal query to OpenAI Completion API https://platform.openai.com/docs/guides/code * note: you must set model to a code model for complete to work i.e model(\"code-cushman-001\")",
+        "\"complete()\"".yellow()
     );
-    println!("");
+    println!();
     println!("{}", "Chat API Commands".bold());
     println!(
-        "{}{}",
-        "\"chat()\"".green()," to send the current terminal query to OpenAI Chat API https://platform.openai.com/docs/guides/chat"
+        "{} to send the current terminal query to OpenAI Chat API https://platform.openai.com/docs/guides/chat",
+        "\"chat()\"".green()
+    );
+    println!(
+        "{} to see the current chat history with numbered messages, each subsequent chat request will include this chat history for context",
+        "\"log()\"".green()
+    );
+    println!(
+        "{} to clear the current chat history and start a new chat from fresh",
+        "\"clear()\"".green()
+    );
+    println!(
+        "{} to export the current chat history to a json file, use a .md or .html file for a readable transcript with the model, settings and token usage",
+        "\"export(\"./path/to/file.json\")\"".green()
+    );
+    println!(
+        "{} to load an exported chat history in place of the current one, pass \"append\" as a second argument to add it to the end instead",
+        "\"import(\"./path/to/file.json\")\"".green()
+    );
+    println!(
+        "{} to set the system message sent at the start of every chat, it is kept by clear(), system(none) removes it and system() shows it",
+        "\"system(\"You are a helpful assistant\")\"".green()
+    );
+    println!(
+        "{} to use a persona file as the system message, personas are read from {} or the directory set in {}",
+        "\"persona(\"rust-reviewer\")\"".green(),
+        persona_dir().map(|dir| dir.display().to_string()).unwrap_or_default(),
+        PERSONA_DIR_ENV
+    );
+    println!(
+        "{} to list the available personas",
+        "\"personas()\"".green()
+    );
+    println!(
+        "{} to clear the chat history and the system message",
+        "\"reset()\"".green()
+    );
+    println!(
+        "{} to save this chat as a named session, it is then saved after every command along with the model, temperature and max_tokens",
+        "\"session(\"name\")\"".green()
+    );
+    println!(
+        "{} to list saved sessions, they are stored in the gptshell/sessions data directory or the directory set in {}",
+        "\"sessions()\"".green(),
+        SESSION_DIR_ENV
+    );
+    println!(
+        "{} to continue a saved session, also see gptshell --session name",
+        "\"resume(\"name\")\"".green()
+    );
+    println!(
+        "{} to change message 3 of log() in $EDITOR, the messages after it are removed and an edited question is sent again",
+        "\"edit(3)\"".green()
+    );
+    println!(
+        "{} to remove the last message sent and its response",
+        "\"undo()\"".green()
+    );
+    println!(
+        "{} to ask for the last response again with the current settings",
+        "\"retry()\"".green()
+    );
+    println!(
+        "{} to start a branch of the chat from here, so another follow up can be tried, the first branch is main",
+        "\"branch(\"alt\")\"".green()
+    );
+    println!(
+        "{} to switch to another branch, see {} for the list",
+        "\"checkout(\"main\")\"".green(),
+        "\"branches()\"".green()
     );
     println!(
-        "{}{}",
-        "\"log()\"".green()," to see the current chat history, each subsequent chat request will include this chat history for context"
+        "{} to keep the second response in the chat history when n(2) or more responses were asked for, the first is kept until another is picked",
+        "\"pick(2)\"".green()
     );
     println!(
-        "{}{}",
-        "\"clear()\"".green(),
-        " to clear the current chat history and start a new chat from fresh"
+        "{} to list the code blocks in the last reply with their languages",
+        "\"blocks()\"".green()
     );
     println!(
-        "{}{}",
-        "\"export(\"./path/to/file.json\")\"".green(),
-        " to export the current chat history to a json file"
+        "{} to write the second code block to a new file",
+        "\"save(2, \"./src/foo.rs\")\"".green()
     );
-    println!("");
+    println!(
+        "{} to see the changes the second code block makes to a file as a diff and write it once confirmed",
+        "\"apply(2, \"./src/foo.rs\")\"".green()
+    );
+    println!(
+        "{} to send the query asking for the changes as a unified diff against the files loaded with file(), the diff is previewed and applied once confirmed",
+        "\"patch()\"".green()
+    );
+    println!(
+        "{} to restore the files changed by the last applied patch",
+        "\"unpatch()\"".green()
+    );
+    println!();
     println!("{}", "API Configuration".bold());
-    println!("{}", "Note: currently for both chat and completion you can only change model,max_tokens and temperature. This will be expanded.");
-    println!("");
     println!(
-        "{}{}",
-        "\"models()\"".cyan(), " to see a list of models, also see https://platform.openai.com/docs/models incase the model you require is not listed, you can still supply it as a string i.e \"model-name-001\""
+        "{} to count the tokens in the query and chat history against the model's limit, requests that cannot fit are refused",
+        "\"tokens()\"".cyan()
+    );
+    println!(
+        "{} to choose what happens when the chat history no longer fits the model, \"off\" refuses the request (default), \"drop_oldest\" drops the oldest turns, \"keep_last\" keeps the system message and the last 4 turns and \"summarize\" summarises all but the last 4 turns",
+        "\"truncate(\"keep_last\",4)\"".cyan()
+    );
+    println!(
+        "{} to see a list of models, also see https://platform.openai.com/docs/models incase the model you require is not listed, you can still supply it as a string i.e \"model-name-001\"",
+        "\"models()\"".cyan()
+    );
+    println!(
+        "{} to set a model i.e model(\"code-cushman-001\")",
+        "\"model(\"model\")\"".cyan()
+    );
+    println!(
+        "{} to set max_tokens, default is 300",
+        "\"max_tokens(3000)\"".cyan()
     );
     println!(
-        "{}{}",
-        "\"model(\"model\")\"".cyan(),
-        " to set a model i.e model(\"code-cushman-001\")"
+        "{} to set temperature, default is 0.7, allowed values are 0 to 1",
+        "\"temperature(0.5)\"".cyan()
     );
     println!(
-        "{}{}",
-        "\"max_tokens(3000)\"".cyan(),
-        " to set max_tokens, default is 300"
+        "{} to print responses as they are generated, default is true",
+        "\"stream(true)\"".cyan()
     );
     println!(
-        "{}{}",
-        "\"temperature(0.5)\"".cyan(),
-        " to set temperature, default is 0.7, allowed values are 0 to 1"
+        "{} to prefix the lines loaded by file(), files(), tree() and fn() with their line numbers so replies can refer to them, default is false",
+        "\"line_numbers(true)\"".cyan()
     );
-    println!("");
+    println!(
+        "{} to set stop sequences, up to 4, \\n in a sequence is a line break",
+        "\"stop(\"sequence\",\"sequence\")\"".cyan()
+    );
+    println!("{} to ask for more than one response", "\"n(2)\"".cyan());
+    println!(
+        "{} to set nucleus sampling, allowed values are 0 to 1",
+        "\"top_p(0.9)\"".cyan()
+    );
+    println!(
+        "{} to penalise tokens that have already appeared, allowed values are -2 to 2",
+        "\"presence_penalty(0.5)\"".cyan()
+    );
+    println!(
+        "{} to penalise tokens by how often they have appeared, allowed values are -2 to 2",
+        "\"frequency_penalty(0.5)\"".cyan()
+    );
+    println!(
+        "{} to change the likelihood of a token id, bias is -100 to 100",
+        "\"logit_bias(50256,-100)\"".cyan()
+    );
+    println!(
+        "{} to send an id for the end user",
+        "\"user(\"name\")\"".cyan()
+    );
+    println!(
+        "{} to sample deterministically (chat only)",
+        "\"seed(42)\"".cyan()
+    );
+    println!(
+        "{} to echo back the prompt with the completion (completion only)",
+        "\"echo(true)\"".cyan()
+    );
+    println!(
+        "{} to generate several completions server side and return the best (completion only)",
+        "\"best_of(3)\"".cyan()
+    );
+    println!(
+        "{} to return the log probabilities of the most likely tokens, up to 5, complete() then highlights tokens the model was unsure about (completion only)",
+        "\"logprobs(5)\"".cyan()
+    );
+    println!(
+        "{} to set the chat response format to \"text\" or \"json_object\"",
+        "\"response_format(\"json_object\")\"".cyan()
+    );
+    println!(
+        "{} to show the current settings, run any setting with none i.e top_p(none) to unset it",
+        "\"settings()\"".cyan()
+    );
+    println!(
+        "{} to set how many times a request is attempted when rate limited or the API is unavailable, default is 3",
+        "\"retries(5)\"".cyan()
+    );
+    println!();
 }
 
 fn run_pwd_cmd() {
@@ -121,20 +325,172 @@ fn run_pwd_cmd() {
         Ok(dir) => println!("Current working directory is: {:?}", dir),
         Err(e) => println!("Error: {:?}", e),
     }
-    println!("");
+    println!();
 }
 
-fn run_cmd(captures: Captures) -> String {
-    let args: Vec<&str> = captures[1].split(",").collect();
-    let command = args[0].trim_matches('"').parse::<String>().unwrap();
-    let output = Command::new("sh")
+// Changes the working directory for cd(), to the home directory when none is given
+fn change_dir(call: &Call) -> Result<(), String> {
+    let home = dirs::home_dir().ok_or("unable to find the home directory");
+    let dir = match call.text(0, "dir") {
+        None => home?,
+        Some(dir) if dir == "~" => home?,
+        Some(dir) => match dir.strip_prefix("~/") {
+            Some(rest) => home?.join(rest),
+            None => PathBuf::from(dir),
+        },
+    };
+    env::set_current_dir(&dir)
+        .map_err(|e| format!("unable to change to {}: {}", dir.display(), e))?;
+    run_pwd_cmd();
+    Ok(())
+}
+
+// How often a running cmd() is checked on while waiting for output
+const CMD_POLL: Duration = Duration::from_millis(50);
+
+// How long output is still read for after the command exits, in case something it started in
+// the background holds on to its stdout
+const CMD_GRACE: Duration = Duration::from_millis(200);
+
+enum CmdOutput {
+    Stdout(String),
+    Stderr(String),
+}
+
+// Sends each line read from `pipe` down `sender`
+fn read_lines<R: io::Read + Send + 'static>(
+    pipe: R,
+    sender: mpsc::Sender<CmdOutput>,
+    wrap: fn(String) -> CmdOutput,
+) {
+    thread::spawn(move || {
+        let mut reader = io::BufReader::new(pipe);
+        let mut line = vec![];
+        while matches!(reader.read_until(b'\n', &mut line), Ok(read) if read > 0) {
+            if sender
+                .send(wrap(String::from_utf8_lossy(&line).to_string()))
+                .is_err()
+            {
+                break;
+            }
+            line.clear();
+        }
+    });
+}
+
+// Runs a shell command for cmd(), printing its output as it arrives and returning it for the
+// query along with how the command exited
+fn run_cmd(call: &Call) -> Result<String, String> {
+    let command = call.required::<String>(0, "command")?;
+    let timeout = match call.get::<f64>(1, "timeout")? {
+        Some(seconds) if seconds <= 0.0 => {
+            return Err(String::from("timeout must be a number of seconds above 0"))
+        }
+        timeout => timeout,
+    };
+    let mut shell = Command::new("sh");
+    shell
         .arg("-c")
-        .arg(command)
-        .output()
-        .expect("Failed to execute command");
-    println!("stdout:\n {}", String::from_utf8_lossy(&output.stdout));
-    println!("stderr:\n {}", String::from_utf8_lossy(&output.stderr));
-    format!("stdout:\n{}\n stderr:\n{}\n", String::from_utf8_lossy(&output.stdout).to_string(), String::from_utf8_lossy(&output.stderr).to_string())
+        .arg(&command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so a timeout kills everything the shell started too
+        .process_group(0);
+    if let Some(cwd) = call.text(1, "cwd") {
+        if !Path::new(&cwd).is_dir() {
+            return Err(format!("{} is not a directory", cwd));
+        }
+        shell.current_dir(cwd);
+    }
+    // Variables are given as KEY=VALUE pairs separated by spaces
+    if let Some(vars) = call.text(1, "env") {
+        for var in vars.split_whitespace() {
+            match var.split_once('=') {
+                Some((key, value)) if !key.is_empty() => shell.env(key, value),
+                _ => return Err(format!("{} is not a KEY=VALUE pair", var)),
+            };
+        }
+    }
+    let mut child = shell
+        .spawn()
+        .map_err(|e| format!("unable to run the command: {}", e))?;
+    let (sender, receiver) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        read_lines(stdout, sender.clone(), CmdOutput::Stdout);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        read_lines(stderr, sender, CmdOutput::Stderr);
+    }
+
+    let started = Instant::now();
+    let (mut stdout, mut stderr) = (String::new(), String::new());
+    let mut status = None;
+    let mut exited_at = None;
+    let mut timed_out = false;
+    loop {
+        match receiver.recv_timeout(CMD_POLL) {
+            Ok(CmdOutput::Stdout(line)) => {
+                print!("{}", line);
+                _ = io::stdout().flush();
+                stdout.push_str(&line);
+                continue;
+            }
+            Ok(CmdOutput::Stderr(line)) => {
+                eprint!("{}", line);
+                stderr.push_str(&line);
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) if status.is_some() => break,
+            // Both pipes are closed, but the command has not exited yet
+            Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(CMD_POLL),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+        if status.is_none() {
+            status = child
+                .try_wait()
+                .map_err(|e| format!("unable to wait for the command: {}", e))?;
+            if status.is_some() {
+                exited_at = Some(Instant::now());
+            }
+        }
+        if exited_at.is_some_and(|exited: Instant| exited.elapsed() > CMD_GRACE) {
+            break;
+        }
+        if status.is_none()
+            && timeout.is_some_and(|seconds| started.elapsed().as_secs_f64() > seconds)
+        {
+            // The group id is the shell's pid
+            _ = Command::new("kill")
+                .args(["-KILL", "--", &format!("-{}", child.id())])
+                .stderr(Stdio::null())
+                .status();
+            _ = child.kill();
+            status = child.wait().ok();
+            exited_at = Some(Instant::now());
+            timed_out = true;
+        }
+    }
+
+    let exit = match (timed_out, status.and_then(|status| status.code())) {
+        (true, _) => format!(
+            "timed out after {}s and was killed",
+            timeout.unwrap_or_default()
+        ),
+        (false, Some(code)) => format!("exit status: {}", code),
+        (false, None) => String::from("killed by a signal"),
+    };
+    let success = !timed_out && status.is_some_and(|status| status.success());
+    println!("{}", if success { exit.green() } else { exit.red() });
+    let mut output = format!("$ {}\n", command);
+    for (name, text) in [("stdout", &stdout), ("stderr", &stderr)] {
+        if !text.is_empty() {
+            output.push_str(&format!("{}:\n{}\n", name, text.trim_end_matches('\n')));
+        }
+    }
+    output.push_str(&exit);
+    output.push('\n');
+    Ok(output)
 }
 
 fn print_all_models() {
@@ -145,96 +501,721 @@ fn print_all_models() {
             model.max_tokens()
         );
         println!("Description: {}", model.description());
-        println!("");
+        println!();
     }
     println!(
         "{}",
         "To set a model, run model(\"model\") i.e model(\"code-cushman-001\")".yellow()
     );
-    println!("");
+    println!();
 }
 
-fn string_to_vec(s: &str) -> Vec<String> {
-    vec![String::from(s)]
+fn get_max_tokens(call: &Call) -> Result<i32, String> {
+    let max_tokens = call.required::<i32>(0, "max_tokens")?;
+    if max_tokens < 1 {
+        return Err(String::from("max_tokens must be at least 1"));
+    }
+    Ok(max_tokens)
 }
 
-fn get_max_tokens(captures: Captures) -> i32 {
-    let args: Vec<&str> = captures[1].split(",").collect();
-    args[0].parse::<i32>().unwrap()
+fn check_temperature_input(call: &Call) -> Result<f64, String> {
+    let temperature = call.required::<f64>(0, "temperature")?;
+    if !(0.0..=1.0).contains(&temperature) {
+        return Err(String::from("temperature must be between 0 and 1"));
+    }
+    Ok(temperature)
 }
 
-fn check_model_input(captures: Captures) -> Models {
-    let args: Vec<&str> = captures[1].split(",").collect();
-    let model = args[0].trim_matches('"').parse::<String>().unwrap();
-    get_model(&model)
+fn check_retries_input(call: &Call) -> Result<u32, String> {
+    let attempts = call.required::<u32>(0, "retries")?;
+    if attempts == 0 {
+        return Err(String::from(
+            "retries must be a whole number greater than 0",
+        ));
+    }
+    Ok(attempts)
 }
 
-fn check_temperature_input(captures: Captures) -> f64 {
-    let args: Vec<&str> = captures[1].split(",").collect();
-    let temperature = args[0].parse::<f64>().unwrap();
-    let default = 0.7;
-    if temperature > 1.0 || temperature < 0.0 {
-        return default;
+fn check_pick_input(call: &Call, candidates: usize) -> Result<usize, String> {
+    if candidates == 0 {
+        return Err(String::from(
+            "there are no responses to pick from, set n(2) or more before chat()",
+        ));
     }
-    temperature
+    let number = call.required::<usize>(0, "response")?;
+    if !(1..=candidates).contains(&number) {
+        return Err(format!("pick a response from 1 to {}", candidates));
+    }
+    Ok(number)
+}
+
+// Prints each streamed delta as it arrives, stopping the spinner on the first one
+fn print_delta(spinner: &mut Option<Spinner>, delta: &str) {
+    if let Some(spinner) = spinner.take() {
+        spinner.stop();
+    }
+    print!("{}", delta);
+    _ = std::io::stdout().flush();
 }
 
-fn export_chat_to_ouput(captures: Captures, chat_history: Vec<Message>) {
-    let args: Vec<&str> = captures[1].split(",").collect();
-    let file_path = args[0].trim_matches('"').parse::<String>().unwrap();
-    let json = serde_json::to_string(&chat_history);
-    match json {
-        Ok(json) => {
-            println!("Saving chat history to {}", file_path);
-            _ = fs::write(file_path, json);
+// Writes json, or a markdown or html transcript depending on the file extension
+fn export_chat_to_ouput(
+    file_path: &str,
+    chat_history: Vec<Message>,
+    settings: &Settings,
+    started: DateTime<Local>,
+) {
+    let transcript = Transcript {
+        messages: &chat_history,
+        model: settings.model.name().to_string(),
+        temperature: settings.temperature,
+        max_tokens: settings.max_tokens,
+        started,
+        exported: Local::now(),
+        tokens: Encoding::for_model(&settings.model).count_messages(&chat_history),
+    };
+    let output = match Format::from_path(file_path) {
+        Format::Json => serde_json::to_string(&chat_history).ok(),
+        Format::Markdown => Some(transcript.to_markdown()),
+        Format::Html => Some(transcript.to_html()),
+    };
+    if let Some(output) = output {
+        println!("Saving chat history to {}", file_path);
+        if let Err(e) = fs::write(file_path, output) {
+            eprintln!("{} unable to write {}: {}", "Error".red(), file_path, e);
         }
-        Err(_) => {}
     }
 }
 
-fn parse_file(captures: Captures, print: bool) -> String {
-    let args: Vec<&str> = captures[1].split(",").collect();
-    if args.len() > 3 {
-        eprintln!("Error {}", "Only maximum of 3 args are allowed".red());
+// Loads a chat history written by export(), replacing the current one unless "append" is passed
+fn import_chat(
+    call: &Call,
+    chat_history: &mut chat::GptChat,
+    settings: &Settings,
+) -> Result<(), String> {
+    let file_path = call.required::<String>(0, "path")?;
+    let append = match call.text(1, "mode").as_deref() {
+        None | Some("replace") => false,
+        Some("append") => true,
+        Some(mode) => {
+            return Err(format!(
+                "{} is not a mode, use \"replace\" or \"append\"",
+                mode
+            ))
+        }
+    };
+    let messages = fs::read_to_string(&file_path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| chat::parse_messages(&json))
+        .map_err(|e| format!("unable to import {}: {}", file_path, e))?;
+    let encoding = Encoding::for_model(&settings.model);
+    let turns = messages
+        .iter()
+        .filter(|message| message.role.as_deref() == Some("user"))
+        .count();
+    let tokens = encoding.count_messages(&messages);
+    if !append {
+        chat_history.reset();
     }
-    //HACK: to handle strings inside quotes (need to convert this into a actual parser with grammar)
-    let file_path = args[0].trim_matches('"');
-    let contents = std::fs::read_to_string(file_path);
+    chat_history.extend(messages);
+    println!(
+        "Imported {} turns ({} tokens) from {}, the chat history is now {} of {} tokens",
+        turns,
+        tokens,
+        file_path,
+        encoding.count_messages(&chat_history.get_all()),
+        settings.model.max_tokens()
+    );
+    Ok(())
+}
+
+fn parse_file(call: &Call, print: bool, line_numbers: bool) -> Result<String, String> {
+    let file_path = call.required::<String>(0, "path")?;
+    let start = call.get::<usize>(1, "start")?.unwrap_or(1).max(1);
+    let end = call.get::<usize>(2, "end")?.unwrap_or(usize::MAX);
+    if end < start {
+        return Err(format!("end must be at least start ({})", start));
+    }
+    // Line to put the insert marker before, for complete() to fill in
+    let cursor = call.get::<usize>(3, "cursor")?;
+    if let Some(cursor) = cursor {
+        // The line after the range puts the marker at its end
+        if cursor < start || cursor > end.saturating_add(1) {
+            return Err(format!(
+                "cursor must be a line from start ({}) to one after end",
+                start
+            ));
+        }
+    }
+    let lines = std::fs::read_to_string(&file_path)
+        .map_err(|e| format!("unable to read {}: {}", file_path, e))?;
     let mut contents_to_use = String::from("");
-    match contents {
-        Ok(lines) => {
-            for (index, line) in lines.lines().enumerate() {
-                if args.len() > 1 {
-                    let start = args[1].trim().parse::<usize>().unwrap();
-                    if index < (start - 1) {
-                        continue;
-                    }
-                }
-                if args.len() > 2 {
-                    let end = args[2].trim().parse::<usize>().unwrap();
-                    if index > (end - 1) {
-                        continue;
-                    }
-                }
-                let new_line = format!("{}\n", line);
-                contents_to_use.push_str(&new_line);
-                if print {
-                    println!("{}: {}", index, line.blue());
+    for (index, line) in lines.lines().enumerate() {
+        if cursor == Some(index + 1) {
+            contents_to_use.push_str(INSERT_MARKER);
+        }
+        if index + 1 < start || index + 1 > end {
+            continue;
+        }
+        let new_line = match line_numbers {
+            true => number_lines(line, index + 1),
+            false => format!("{}\n", line),
+        };
+        contents_to_use.push_str(&new_line);
+        if print {
+            println!("{}: {}", index + 1, line.blue());
+        }
+    }
+    if cursor.is_some() && !contents_to_use.contains(INSERT_MARKER) {
+        contents_to_use.push_str(INSERT_MARKER);
+    }
+    Ok(contents_to_use)
+}
+
+// Loads a Rust item for fn(), i.e fn("src/repl.rs", "run_repl") or fn("src/settings.rs",
+// "Settings::print"), returning its path and lines
+fn load_item(call: &Call, line_numbers: bool) -> Result<(String, String), String> {
+    let path = call.required::<String>(0, "path")?;
+    let name = call.required::<String>(1, "name")?;
+    let source =
+        fs::read_to_string(&path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    let (start, end) = find_item(&source, &name).ok_or_else(|| {
+        format!(
+            "there is no fn, struct, enum, trait, impl or mod named {} in {}",
+            name, path
+        )
+    })?;
+    let lines: Vec<&str> = source
+        .lines()
+        .skip(start - 1)
+        .take(end - start + 1)
+        .collect();
+    let text = match line_numbers {
+        true => number_lines(&lines.join("\n"), start),
+        false => format!("{}\n", lines.join("\n")),
+    };
+    println!(
+        "Loaded {} from {} lines {} to {}",
+        name.bold(),
+        path,
+        start,
+        end
+    );
+    Ok((path, text))
+}
+
+// Paths under `root` as an indented tree
+fn print_tree(root: &str, files: &[LoadedFile]) {
+    println!("{}", root.bold());
+    let root = root.trim_start_matches("./").trim_end_matches('/');
+    let mut shown: Vec<String> = vec![];
+    for file in files {
+        let relative = match root {
+            "." | "" => file.path.as_str(),
+            root => file
+                .path
+                .strip_prefix(&format!("{}/", root))
+                .unwrap_or(&file.path),
+        };
+        let parts: Vec<&str> = relative.split('/').collect();
+        for depth in 0..parts.len() - 1 {
+            let dir = parts[..=depth].join("/");
+            if !shown.contains(&dir) {
+                println!("{}{}/", "  ".repeat(depth + 1), parts[depth]);
+                shown.push(dir);
+            }
+        }
+        println!(
+            "{}{}",
+            "  ".repeat(parts.len()),
+            parts[parts.len() - 1].blue()
+        );
+    }
+}
+
+// Loads the files matching a glob for files() or under a directory for tree() into the query,
+// each under its path in a fence
+fn load_files(
+    call: &Call,
+    settings: &Settings,
+    history: &mut String,
+    files: &mut Vec<String>,
+) -> Result<(), String> {
+    let (source, loaded) = match call.name.as_str() {
+        "tree" => {
+            let dir = call.text(0, "dir").unwrap_or_else(|| String::from("."));
+            let loaded = dir_files(&dir)?;
+            print_tree(&dir, &loaded.files);
+            (dir, loaded)
+        }
+        _ => {
+            let pattern = call.required::<String>(0, "pattern")?;
+            let loaded = glob_files(&pattern)?;
+            for file in &loaded.files {
+                println!(
+                    "{} {} lines",
+                    file.path.blue(),
+                    file.contents.lines().count()
+                );
+            }
+            (pattern, loaded)
+        }
+    };
+    for path in &loaded.binaries {
+        println!("{} {}, it is a binary file", "Skipped".yellow(), path);
+    }
+    if loaded.files.is_empty() {
+        return Err(format!("there are no text files in {}", source));
+    }
+    for file in &loaded.files {
+        history.push_str(&file.to_query(settings.line_numbers));
+        if !files.contains(&file.path) {
+            files.push(file.path.clone());
+        }
+    }
+    let tokens = Encoding::for_model(&settings.model).count(history);
+    println!(
+        "Loaded {} files, the query is {} tokens",
+        loaded.files.len(),
+        tokens
+    );
+    if tokens > settings.model.max_tokens() as usize {
+        println!(
+            "{}",
+            format!(
+                "Warning: that is more than the {} tokens {} can take, empty() the query and load fewer files or add them to {}",
+                settings.model.max_tokens(),
+                settings.model.name(),
+                IGNORE_FILE
+            )
+            .yellow()
+        );
+    }
+    Ok(())
+}
+
+// A fenced code block from a reply and the language it was tagged with
+struct CodeBlock {
+    language: Option<String>,
+    code: String,
+}
+
+fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = vec![];
+    let mut current: Option<CodeBlock> = None;
+    for event in Parser::new(text) {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let language = info.split_whitespace().next().map(String::from);
+                current = Some(CodeBlock {
+                    language,
+                    code: String::new(),
+                });
+            }
+            Event::Text(text) => {
+                if let Some(block) = &mut current {
+                    block.code.push_str(&text);
                 }
             }
+            Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(_))) => {
+                blocks.extend(current.take());
+            }
+            _ => {}
         }
-        Err(e) => {
-            eprintln!("Error {:?}", e);
+    }
+    blocks
+}
+
+// Code blocks of the last reply in the chat
+fn last_reply(chat_history: &chat::GptChat) -> Result<&str, String> {
+    chat_history
+        .messages()
+        .iter()
+        .rev()
+        .find(|message| message.role.as_deref() == Some("assistant"))
+        .and_then(|message| message.content.as_deref())
+        .ok_or_else(|| String::from("there is no reply yet, run chat() first"))
+}
+
+fn last_reply_blocks(chat_history: &chat::GptChat) -> Result<Vec<CodeBlock>, String> {
+    let blocks = code_blocks(last_reply(chat_history)?);
+    if blocks.is_empty() {
+        return Err(String::from("the last reply has no code blocks"));
+    }
+    Ok(blocks)
+}
+
+fn print_blocks(chat_history: &chat::GptChat) -> Result<(), String> {
+    for (index, block) in last_reply_blocks(chat_history)?.iter().enumerate() {
+        let first_line = block.code.lines().find(|line| !line.trim().is_empty());
+        println!(
+            "[{}] {} {} lines: {}",
+            index + 1,
+            block.language.as_deref().unwrap_or("text").bold(),
+            block.code.lines().count(),
+            first_line.unwrap_or("").trim().blue()
+        );
+    }
+    Ok(())
+}
+
+// The block picked by the first argument of save() and apply() and the path to write it to
+fn get_block(call: &Call, chat_history: &chat::GptChat) -> Result<(CodeBlock, String), String> {
+    let number = call.required::<usize>(0, "block")?;
+    let path = call.required::<String>(1, "path")?;
+    let mut blocks = last_reply_blocks(chat_history)?;
+    if !(1..=blocks.len()).contains(&number) {
+        return Err(format!(
+            "pick a code block from 1 to {}, see blocks()",
+            blocks.len()
+        ));
+    }
+    Ok((blocks.swap_remove(number - 1), path))
+}
+
+fn save_block(call: &Call, chat_history: &chat::GptChat) -> Result<(), String> {
+    let (block, path) = get_block(call, chat_history)?;
+    if Path::new(&path).exists() {
+        return Err(format!(
+            "{} already exists, use apply() to review the changes before overwriting it",
+            path
+        ));
+    }
+    fs::write(&path, &block.code).map_err(|e| format!("unable to write {}: {}", path, e))?;
+    println!("Saved {} lines to {}", block.code.lines().count(), path);
+    Ok(())
+}
+
+fn print_diff(path: &str, old: &str, new: &str) {
+    let diff = TextDiff::from_lines(old, new);
+    let unified = diff
+        .unified_diff()
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string();
+    for line in unified.lines() {
+        if line.starts_with("+++") || line.starts_with("---") {
+            println!("{}", line.bold());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// Shows the changes a code block makes to a file and writes it once they are confirmed
+fn apply_block(call: &Call, chat_history: &chat::GptChat) -> Result<(), String> {
+    let (block, path) = get_block(call, chat_history)?;
+    let existing = match fs::read_to_string(&path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("unable to read {}: {}", path, e)),
+    };
+    if existing == block.code {
+        println!("{} already matches the code block", path);
+        return Ok(());
+    }
+    print_diff(&path, &existing, &block.code);
+    if !confirm(&format!("Write these changes to {}?", path)) {
+        println!("{} was not changed", path);
+        return Ok(());
+    }
+    fs::write(&path, &block.code).map_err(|e| format!("unable to write {}: {}", path, e))?;
+    println!("Applied the changes to {}", path);
+    Ok(())
+}
+
+const PATCH_INSTRUCTION: &str = "Reply with the changes as a unified diff in one ```diff code block, with --- a/path and +++ b/path headers and 3 lines of context around each change, and do not repeat code that is not changing.";
+
+// Files and their contents, None when a file does not exist. An applied patch keeps one of what
+// the files held before for unpatch().
+type Snapshot = Vec<(String, Option<String>)>;
+
+fn patch_prompt(history: &str, files: &[String]) -> String {
+    let mut prompt = format!("{}\n{}", history.trim_end(), PATCH_INSTRUCTION);
+    if !files.is_empty() {
+        prompt.push_str(&format!(" The files are {}.", files.join(", ")));
+    }
+    prompt
+}
+
+// The diff in the reply to patch(), taken from its diff code blocks if it has any. The reply has
+// to be the last message so an older one is never applied.
+fn reply_patch(chat_history: &chat::GptChat) -> Result<Vec<FilePatch>, String> {
+    let reply = match chat_history.messages().last() {
+        Some(message) if message.role.as_deref() == Some("assistant") => {
+            message.content.as_deref().unwrap_or("")
+        }
+        _ => return Err(String::from("there is no reply to the patch request")),
+    };
+    let diff: Vec<String> = code_blocks(reply)
+        .into_iter()
+        .filter(|block| matches!(block.language.as_deref(), Some("diff" | "patch")))
+        .map(|block| block.code)
+        .collect();
+    match diff.is_empty() {
+        true => parse_patch(reply),
+        false => parse_patch(&diff.join("\n")),
+    }
+}
+
+// Checks the paths in a patch, which come from the model, returning the ones that weren't loaded
+// with file() so they can be confirmed separately
+fn unloaded_paths(files: &[FilePatch], loaded: &[String]) -> Result<Vec<String>, String> {
+    let loaded: Vec<&str> = loaded
+        .iter()
+        .map(|path| path.trim_start_matches("./"))
+        .collect();
+    let (mut seen, mut unloaded) = (vec![], vec![]);
+    for file in files {
+        for path in [&file.old_path, &file.new_path].into_iter().flatten() {
+            let path = Path::new(path);
+            if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+                return Err(format!(
+                    "{} is outside the working directory",
+                    path.display()
+                ));
+            }
+        }
+        let path = file.path().trim_start_matches("./");
+        if path.is_empty() {
+            return Err(String::from("the patch has a file without a path"));
+        }
+        if seen.contains(&path) {
+            return Err(format!("the patch changes {} more than once", path));
+        }
+        seen.push(path);
+        if !loaded.contains(&path) {
+            unloaded.push(path.to_string());
+        }
+    }
+    Ok(unloaded)
+}
+
+// Patches every file in memory first so nothing is written unless all of the hunks apply,
+// returning the files as they are and as they will be
+fn prepare_patch(files: &[FilePatch]) -> Result<(Snapshot, Snapshot), String> {
+    let (mut originals, mut patches) = (vec![], vec![]);
+    for file in files {
+        let path = file.path().trim_start_matches("./").to_string();
+        let original = match fs::read_to_string(&path) {
+            Ok(original) => Some(original),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("unable to read {}: {}", path, e)),
+        };
+        match (&file.old_path, &original) {
+            (Some(_), None) => return Err(format!("{} does not exist", path)),
+            (None, Some(_)) => return Err(format!("{} already exists", path)),
+            _ => {}
+        }
+        let patched = file.apply(original.as_deref().unwrap_or(""))?;
+        originals.push((path.clone(), original));
+        patches.push((path, patched));
+    }
+    Ok((originals, patches))
+}
+
+// Writes each file's contents, or removes it for None
+fn write_files(files: &[(String, Option<String>)]) -> Result<(), String> {
+    for (path, contents) in files {
+        let written = match contents {
+            Some(contents) => Path::new(path)
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(path, contents)),
+            None => fs::remove_file(path),
+        };
+        written.map_err(|e| format!("unable to write {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+// Previews the patch in the last reply and applies it once confirmed, keeping a snapshot of the
+// files for unpatch()
+fn apply_patch(
+    chat_history: &chat::GptChat,
+    files: &[String],
+    snapshots: &mut Vec<Snapshot>,
+) -> Result<(), String> {
+    let patch = reply_patch(chat_history)?;
+    let unloaded = unloaded_paths(&patch, files)?;
+    let (snapshot, patched) = prepare_patch(&patch)?;
+    for ((path, original), (_, patched)) in snapshot.iter().zip(&patched) {
+        print_diff(
+            path,
+            original.as_deref().unwrap_or(""),
+            patched.as_deref().unwrap_or(""),
+        );
+    }
+    if !unloaded.is_empty()
+        && !confirm(&format!(
+            "The patch also creates or changes {}, which weren't loaded with file(). Allow that?",
+            unloaded.join(", ")
+        ))
+    {
+        println!("The patch was not applied");
+        return Ok(());
+    }
+    if !confirm(&format!("Apply the patch to {} files?", patched.len())) {
+        println!("The patch was not applied");
+        return Ok(());
+    }
+    if let Err(e) = write_files(&patched) {
+        _ = write_files(&snapshot);
+        return Err(e);
+    }
+    println!(
+        "Applied the patch to {}, run unpatch() to undo it",
+        snapshot
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+    snapshots.push(snapshot);
+    Ok(())
+}
+
+// Shows how much of the model's context window the query and chat history use
+fn print_token_usage(settings: &Settings, chat_history: &chat::GptChat, history: &str) {
+    let encoding = Encoding::for_model(&settings.model);
+    let query_tokens = encoding.count(&history.replace(INSERT_MARKER, ""));
+    let mut messages = chat_history.get_all();
+    let history_tokens = encoding.count_messages(&messages);
+    messages.push(generate_message_from_prompt(history));
+    let chat_tokens = encoding.count_messages(&messages);
+    let limit = settings.model.max_tokens().max(0) as usize;
+    let room = |used: usize| match limit.saturating_sub(used) {
+        0 if used > limit => format!("{} over the limit", used - limit),
+        left => format!("leaving {} for the response", left),
+    };
+    println!("Query: {} tokens", query_tokens);
+    println!("Chat history: {} tokens", history_tokens);
+    println!(
+        "chat() would use {} of {} tokens for {}, {}, max_tokens is {}",
+        chat_tokens,
+        limit,
+        settings.model.name(),
+        room(chat_tokens),
+        settings.max_tokens
+    );
+    println!(
+        "complete() would use {} of {} tokens, {}",
+        query_tokens,
+        limit,
+        room(query_tokens)
+    );
+    if let Err(e) = check_context(&settings.model, chat_tokens, settings.max_tokens) {
+        println!("{}", format!("chat() will be refused: {}", e).yellow());
+    }
+    println!();
+}
+
+fn print_personas() {
+    let Some(dir) = persona_dir() else {
+        eprintln!("{}", "Error: unable to find the config directory".red());
+        return;
+    };
+    match list_personas(&dir) {
+        Ok(names) if names.is_empty() => println!("There are no personas in {}", dir.display()),
+        Ok(names) => {
+            for name in names {
+                println!("{}", name);
+            }
         }
+        Err(e) => eprintln!("{} {}: {}", "Error reading".red(), dir.display(), e),
     }
-    contents_to_use
+    println!();
 }
 
-fn exit_with_error(message: &str) {
+fn get_persona(call: &Call) -> Result<String, String> {
+    let name = call.required::<String>(0, "name")?;
+    let dir = persona_dir().ok_or("unable to find the config directory")?;
+    load_persona(&dir, &name).map_err(|e| e.to_string())
+}
+
+fn exit_with_error(message: &str) -> ! {
     eprintln!("Error: {}", message.red());
     std::process::exit(1);
 }
 
+// Commands with the most positional arguments they take and the names they can be given by,
+// the options in `OPTIONS` take up to 4. The names are also shown as hints while typing.
+const COMMANDS: [(&str, Spec); 47] = [
+    ("exit", (0, &[])),
+    ("help", (0, &[])),
+    ("print", (0, &[])),
+    ("empty", (0, &[])),
+    ("clear", (0, &[])),
+    ("reset", (0, &[])),
+    ("sessions", (0, &[])),
+    ("personas", (0, &[])),
+    ("log", (0, &[])),
+    ("undo", (0, &[])),
+    ("retry", (0, &[])),
+    ("branches", (0, &[])),
+    ("tokens", (0, &[])),
+    ("chat", (0, &[])),
+    ("complete", (0, &[])),
+    ("settings", (0, &[])),
+    ("models", (0, &[])),
+    ("pwd", (0, &[])),
+    ("blocks", (0, &[])),
+    ("save", (2, &["block", "path"])),
+    ("apply", (2, &["block", "path"])),
+    ("patch", (0, &[])),
+    ("unpatch", (0, &[])),
+    ("edit", (1, &["message"])),
+    ("system", (1, &["message"])),
+    ("cat", (3, &["path", "start", "end"])),
+    ("file", (4, &["path", "start", "end", "cursor"])),
+    ("files", (1, &["pattern"])),
+    ("fn", (2, &["path", "name"])),
+    ("line_numbers", (1, &["line_numbers"])),
+    ("tree", (1, &["dir"])),
+    ("export", (1, &["path"])),
+    ("import", (2, &["path", "mode"])),
+    ("max_tokens", (1, &["max_tokens"])),
+    ("model", (1, &["model"])),
+    ("temperature", (1, &["temperature"])),
+    ("stream", (1, &["stream"])),
+    ("retries", (1, &["retries"])),
+    ("pick", (1, &["response"])),
+    ("persona", (1, &["name"])),
+    ("truncate", (2, &["policy", "turns"])),
+    ("session", (1, &["name"])),
+    ("resume", (1, &["name"])),
+    ("branch", (1, &["name"])),
+    ("checkout", (1, &["name"])),
+    ("cmd", (1, &["command", "cwd", "timeout", "env"])),
+    ("cd", (1, &["dir"])),
+];
+
+fn command_spec(name: &str) -> Option<Spec> {
+    COMMANDS
+        .iter()
+        .find(|(command, _)| *command == name)
+        .map(|(_, spec)| *spec)
+        .or_else(|| OPTIONS.contains(&name).then_some((4, &[])))
+}
+
+const MULTILINE_DELIMITER: &str = "\"\"\"";
+
 fn generate_message_from_prompt(prompt: &str) -> chat::Message {
     chat::Message {
         role: Some(String::from("user")),
@@ -242,167 +1223,794 @@ fn generate_message_from_prompt(prompt: &str) -> chat::Message {
     }
 }
 
-pub async fn run_repl() {
+fn save_session(
+    store: &SessionStore,
+    name: &str,
+    chat_history: &chat::GptChat,
+    branches: &Branches,
+    settings: &Settings,
+) -> io::Result<()> {
+    store.save(&Session {
+        name: name.to_string(),
+        chat: chat_history.clone(),
+        branches: branches.clone(),
+        model: settings.model.name().to_string(),
+        temperature: settings.temperature,
+        max_tokens: settings.max_tokens,
+    })
+}
+
+fn resume_session(
+    store: &SessionStore,
+    name: &str,
+    chat_history: &mut chat::GptChat,
+    branches: &mut Branches,
+    settings: &mut Settings,
+) -> io::Result<()> {
+    let session = store.load(name)?;
+    *chat_history = session.chat;
+    *branches = session.branches;
+    settings.model = get_model(&session.model);
+    settings.temperature = session.temperature;
+    settings.max_tokens = session.max_tokens;
+    println!(
+        "Resumed session {} on branch {} with {} messages, model is {}",
+        name.bold(),
+        branches.current(),
+        chat_history.get_all().len(),
+        settings.model.name()
+    );
+    Ok(())
+}
+
+fn print_sessions(store: &SessionStore, current: Option<&str>) {
+    match store.list() {
+        Ok(names) if names.is_empty() => {
+            println!("There are no sessions in {}", store.dir().display())
+        }
+        Ok(names) => {
+            for name in names {
+                if current == Some(name.as_str()) {
+                    println!("{} {}", name.bold(), "(current)".italic());
+                } else {
+                    println!("{}", name);
+                }
+            }
+        }
+        Err(e) => eprintln!("{} {}: {}", "Error reading".red(), store.dir().display(), e),
+    }
+    println!();
+}
+
+// Sends the chat history, which ends with a user message, and adds the response to it.
+// Returns an error when no response was added, because the request would not fit the model or
+// it failed.
+async fn send_chat(
+    client: &OpenAiClient,
+    settings: &Settings,
+    chat_history: &mut chat::GptChat,
+    candidates: &mut Vec<Message>,
+) -> Result<(), String> {
+    let encoding = Encoding::for_model(&settings.model);
+    let fits = |messages: &[Message]| {
+        let prompt_tokens = encoding.count_messages(messages);
+        check_context(&settings.model, prompt_tokens, settings.max_tokens).is_ok()
+    };
+    match truncate(
+        chat_history,
+        settings.truncation,
+        fits,
+        client,
+        settings.model.name(),
+    )
+    .await
+    {
+        Ok(0) => {}
+        Ok(removed) => println!(
+            "{}",
+            format!(
+                "Truncated {} messages from the chat history with {}",
+                removed, settings.truncation
+            )
+            .yellow()
+        ),
+        Err(e) => {
+            eprintln!("{}: {}", "Error summarising the chat history".red(), e)
+        }
+    }
+    let prompt_tokens = encoding.count_messages(&chat_history.get_all());
+    if let Err(e) = check_context(&settings.model, prompt_tokens, settings.max_tokens) {
+        return Err(format!(
+            "{}, lower max_tokens(), clear() the chat history or set a truncate() policy",
+            e
+        ));
+    }
+    candidates.clear();
+
+    let mut spinner = Some(Spinner::new_with_stream(
+        spinners::Dots,
+        "",
+        Color::Yellow,
+        Streams::Stderr,
+    ));
+    let request = settings.chat_request(chat_history.get_all());
+    let mut renderer = MarkdownRenderer::new(color_enabled());
+    let output = if request.stream == Some(true) {
+        client
+            .chat_stream(request, |delta| {
+                print_delta(&mut spinner, &renderer.push(delta))
+            })
+            .await
+    } else {
+        client.chat(request).await
+    };
+    let streamed = spinner.is_none();
+    if let Some(spinner) = spinner {
+        spinner.stop();
+    }
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            // End the part of the reply that was streamed before the error
+            if streamed {
+                println!("{}", renderer.finish());
+            }
+            return Err(e.to_string());
+        }
+    };
+    if !output.save_messages(chat_history) {
+        return Err(String::from("the response has no message"));
+    }
+    if streamed {
+        println!("{}", renderer.finish());
+    } else {
+        output.to_cli();
+    }
+    if output.candidates().len() > 1 {
+        *candidates = output.candidates();
+        println!(
+            "{}",
+            "Response [1] has been kept, run pick(n) to keep another".yellow()
+        );
+    }
+    Ok(())
+}
+
+// Opens the named session from `--session`, starting it if it does not exist yet
+pub async fn run_repl(session_name: Option<String>) {
     let mut history = String::new();
     let mut chat_history = chat::GptChat::new();
-    let mut max_tokens = 300;
-    let mut temperature = 0.7;
-    let mut model = models::Models::Gpt35Turbo;
+    // Responses from the last chat() when more than one was asked for
+    let mut candidates: Vec<Message> = vec![];
+    // Files loaded into this chat with file(), files() or tree(), they are named in patch()
+    // requests
+    let mut files: Vec<String> = vec![];
+    let mut snapshots: Vec<Snapshot> = vec![];
+    let mut settings = Settings::new();
+    let mut branches = Branches::default();
+    // The current session is saved after every command
+    let store = SessionStore::from_env();
+    let mut session: Option<String> = None;
+    let started = Local::now();
     let version: &str = env!("CARGO_PKG_VERSION");
 
-    //TODO: let this take strings in the form function("string", 1, 2) where 1 and 2 are optional arguments
-    //TODO: convert this into a grammar and parse vs using Regex
-    let re_cat_function = Regex::new(r"^cat\(([^)]+)\)").unwrap();
-    let re_max_tokens_function = Regex::new(r"^max_tokens\(([^)]+)\)").unwrap();
-    let re_file_function = Regex::new(r"^file\(([^)]+)\)").unwrap();
-    let re_set_model_function = Regex::new(r"^model\(([^)]+)\)").unwrap();
-    let re_set_temperature_function = Regex::new(r"^temperature\(([^)]+)\)").unwrap();
-    let re_export_chat_function = Regex::new(r"^export\(([^)]+)\)").unwrap();
-    let re_cmd_function = Regex::new(r"^cmd\(([^)]+)\)").unwrap();
-
     println!("{} version: {}", "gptshell".bold(), version.italic());
-    println!("");
+    println!();
     print_quickstart();
-    let api_key = env::var("OPENAI_API_KEY");
-    match api_key.clone() {
-        Ok(_) => {}
+    let mut client = match OpenAiClient::from_env() {
+        Ok(client) => client,
         Err(_) => {
             exit_with_error("Error: OPENAI_API_KEY environment variable is not set, please set it before continuing \n Create an API Key here https://platform.openai.com/account/api-keys ... \n Exiting ... ");
         }
+    };
+
+    if let Some(name) = session_name {
+        let Some(store) = &store else {
+            exit_with_error("unable to find the data directory to store sessions in");
+        };
+        if store.exists(&name) {
+            if let Err(e) = resume_session(
+                store,
+                &name,
+                &mut chat_history,
+                &mut branches,
+                &mut settings,
+            ) {
+                exit_with_error(&format!("unable to resume session {}: {}", name, e));
+            }
+        } else {
+            println!("Starting session {}", name.bold());
+        }
+        session = Some(name);
     }
 
-    let mut rl = DefaultEditor::new().unwrap();
+    // Lines typed between two MULTILINE_DELIMITER lines go straight into the query
+    let mut multiline = false;
+    let mut commands = COMMANDS.to_vec();
+    commands.extend(OPTIONS.iter().map(|option| (*option, (4, &[][..]))));
+    let mut rl: Editor<ReplHelper, DefaultHistory> = Editor::new().unwrap();
+    rl.set_helper(Some(ReplHelper::new(
+        &commands,
+        store.as_ref().map(|store| SessionStore::new(store.dir())),
+    )));
     if rl.load_history("history.txt").is_err() {
-        println!("");
+        println!();
     }
     loop {
-        let readline = rl.readline(">> ");
+        let readline = rl.readline(if multiline { ".. " } else { ">> " });
         match readline {
+            Ok(input) if multiline => {
+                if input.trim() == MULTILINE_DELIMITER {
+                    multiline = false;
+                } else {
+                    history.push_str(&input);
+                    history.push('\n');
+                }
+            }
             Ok(input) => {
-                let history_err = rl.add_history_entry(input.as_str());
-                match history_err {
-                    //TODO: decide how to handle
-                    _ => {}
+                //TODO: decide how to handle
+                _ = rl.add_history_entry(input.as_str());
+                if input.trim() == MULTILINE_DELIMITER {
+                    multiline = true;
+                    continue;
                 }
-                match input.trim() {
-                    "exit()" => break,
-                    "help()" => print_help(),
-                    "print()" => {
+                // Anything that is not a command is added to the query
+                let call = match command::parse(&input, command_spec) {
+                    Ok(Some(call)) => call,
+                    Ok(None) => {
+                        history.push_str(&input);
+                        history.push('\n');
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("{}\n{}", "Error".red(), e.show(&input));
+                        continue;
+                    }
+                };
+                match call.name.as_str() {
+                    "edit" if call.args.is_empty() => match edit_text(&history) {
+                        Ok(edited) => {
+                            history = edited;
+                            println!("Updated the query, print() to see it");
+                        }
+                        Err(e) => eprintln!("{} unable to edit the query: {}", "Error".red(), e),
+                    },
+                    "exit" => break,
+                    "help" => print_help(),
+                    "print" => {
                         println!("{}", history);
                     }
-                    "empty()" => {
+                    "empty" => {
                         history = String::from("");
                     }
-                    "clear()" => {
+                    "clear" => {
+                        files.clear();
                         chat_history.flush();
+                        candidates.clear();
                         history = String::from("");
                     }
-                    "log()" => {
+                    "reset" => {
+                        files.clear();
+                        chat_history.reset();
+                        candidates.clear();
+                        history = String::from("");
+                    }
+                    "system" if call.args.is_empty() => match chat_history.system() {
+                        Some(system) => println!("System message: {}", system.yellow()),
+                        None => {
+                            println!("There is no system message, set one with system(\"...\")")
+                        }
+                    },
+                    "sessions" => match &store {
+                        Some(store) => print_sessions(store, session.as_deref()),
+                        None => eprintln!("{}", "Error: unable to find the data directory".red()),
+                    },
+                    "personas" => {
+                        print_personas();
+                    }
+                    "log" => {
                         let all_chats = chat_history.get_all();
-                        if all_chats.len() == 0 {
+                        if all_chats.is_empty() {
                             println!("There is no current chat history to display");
                             continue;
                         }
                         println!("Current log of chat history: ");
-                        for message in chat_history.get_all() {
-                            let role = message.role.unwrap();
-                            println!("User: {:?}", role);
-                            if role == "user" {
-                                println!("Message: {}", message.content.unwrap().blue());
-                            } else {
-                                println!("Message: {}", message.content.unwrap().green());
+                        for (index, message) in chat_history.get_all().into_iter().enumerate() {
+                            // Imported or edited chats can have messages without a role or content
+                            let role = message.role.as_deref().unwrap_or("unknown");
+                            println!("[{}] User: {:?}", index + 1, role);
+                            match (role, message.content.as_deref()) {
+                                (_, None) => println!("Message: {}", "(no content)".italic()),
+                                ("user", Some(content)) => println!("Message: {}", content.blue()),
+                                ("system", Some(content)) => {
+                                    println!("Message: {}", content.yellow())
+                                }
+                                (_, Some(content)) => println!("Message: {}", content.green()),
                             }
                         }
                         println!("Current query: ")
                     }
-                    "chat()" => {
-                        chat_history.add(generate_message_from_prompt(&history));
-
-                        let spinner = Spinner::new_with_stream(
-                            spinners::Dots,
-                            "",
-                            Color::Yellow,
-                            Streams::Stderr,
-                        );
-                        let request = chat::ChatCreateCompletionParams {
-                            max_tokens: Some(max_tokens),
-                            model: Some(model.name().to_string()),
-                            messages: Some(chat_history.get_all()),
-                            temperature: Some(temperature),
-                        };
-                        let output = chat::process_chat_prompt(request).await;
-                        match output {
-                            Ok(output) => {
-                                spinner.stop();
-                                output.save_messages(&mut chat_history);
-                                output.to_cli();
-                            }
-                            Err(e) => {
-                                spinner.stop();
-                                eprintln!("{}: {:?}", "Error".red(), e)
+                    "undo" => match chat_history.undo() {
+                        0 => println!("There is nothing to undo"),
+                        removed => {
+                            candidates.clear();
+                            println!("Removed the last exchange ({} messages)", removed);
+                        }
+                    },
+                    "retry" => {
+                        if chat_history.turn_starts().is_empty() {
+                            println!("There is no message to retry, send one with chat()");
+                            continue;
+                        }
+                        let reply = chat_history.take_reply();
+                        if let Err(e) =
+                            send_chat(&client, &settings, &mut chat_history, &mut candidates).await
+                        {
+                            eprintln!("{}: {}", "Error".red(), e);
+                            chat_history.extend(reply);
+                        }
+                    }
+                    "branches" => {
+                        for (name, branch) in branches.list(&chat_history) {
+                            let messages = branch.chat.get_all().len();
+                            let line = match branch.parent {
+                                Some(parent) => format!(
+                                    "{} ({} messages, from {} after message {})",
+                                    name, messages, parent, branch.fork_point
+                                ),
+                                None => format!("{} ({} messages)", name, messages),
+                            };
+                            if name == branches.current() {
+                                println!("* {}", line.bold());
+                            } else {
+                                println!("  {}", line);
                             }
                         }
+                        println!();
+                    }
+                    "tokens" => {
+                        print_token_usage(&settings, &chat_history, &history);
+                    }
+                    "chat" => {
+                        chat_history.add(generate_message_from_prompt(&history));
+                        // The query is kept so it can be sent again
+                        if let Err(e) =
+                            send_chat(&client, &settings, &mut chat_history, &mut candidates).await
+                        {
+                            eprintln!("{}: {}", "Error".red(), e);
+                            chat_history.pop();
+                            continue;
+                        }
                         history = String::from("");
                     }
-                    "complete()" => {
-                        let spinner = Spinner::new_with_stream(
+                    "patch" => {
+                        chat_history.add(generate_message_from_prompt(&patch_prompt(
+                            &history, &files,
+                        )));
+                        // Without a new reply there is no diff to apply
+                        if let Err(e) =
+                            send_chat(&client, &settings, &mut chat_history, &mut candidates).await
+                        {
+                            eprintln!("{}: {}", "Error".red(), e);
+                            chat_history.pop();
+                            continue;
+                        }
+                        history = String::from("");
+                        if let Err(e) = apply_patch(&chat_history, &files, &mut snapshots) {
+                            eprintln!(
+                                "{} {}, ask for the changes again with patch()",
+                                "Error".red(),
+                                e
+                            );
+                        }
+                    }
+                    "unpatch" => match snapshots.pop() {
+                        Some(snapshot) => match write_files(&snapshot) {
+                            Ok(_) => println!(
+                                "Restored {}",
+                                snapshot
+                                    .iter()
+                                    .map(|(path, _)| path.as_str())
+                                    .collect::<Vec<&str>>()
+                                    .join(", ")
+                            ),
+                            Err(e) => {
+                                eprintln!("{} {}", "Error".red(), e);
+                                snapshots.push(snapshot);
+                            }
+                        },
+                        None => println!("There is no patch to undo"),
+                    },
+                    "complete" => {
+                        let prompt_tokens = Encoding::for_model(&settings.model)
+                            .count(&history.replace(INSERT_MARKER, ""));
+                        if let Err(e) =
+                            check_context(&settings.model, prompt_tokens, settings.max_tokens)
+                        {
+                            eprintln!(
+                                "{} {}, lower max_tokens() or empty() the query",
+                                "Error".red(),
+                                e
+                            );
+                            continue;
+                        }
+                        let mut spinner = Some(Spinner::new_with_stream(
                             spinners::Dots,
                             "",
                             Color::Yellow,
                             Streams::Stderr,
-                        );
-                        let request = completion::CodeCompletionCreateParams {
-                            max_tokens: max_tokens,
-                            model: model.name().to_string(),
-                            prompt: string_to_vec(&history),
-                            temperature: temperature,
+                        ));
+                        let request = settings.completion_request(&history);
+                        let show_logprobs = settings.logprobs.is_some();
+                        let output = if request.stream == Some(true) {
+                            client
+                                .completion_stream(request, |delta| {
+                                    print_delta(&mut spinner, &delta.green().to_string())
+                                })
+                                .await
+                        } else {
+                            client.completion(request).await
                         };
-                        let output = completion::process_completion_prompt(request).await;
+                        let streamed = spinner.is_none();
+                        if let Some(spinner) = spinner {
+                            spinner.stop();
+                        }
                         match output {
                             Ok(output) => {
-                                spinner.stop();
-                                output.to_cli()
+                                if streamed {
+                                    println!();
+                                } else if show_logprobs {
+                                    println!("{}", output.get_logprobs_output(color_enabled()));
+                                } else {
+                                    output.to_cli()
+                                }
                             }
                             Err(e) => {
-                                spinner.stop();
                                 eprintln!("Note: you must set model to a code model for complete to work i.e model(\"code-cushman-001\")");
-                                eprintln!("{}: {:?}", "Completion Error".red(), e)
+                                eprintln!("{}: {}", "Completion Error".red(), e)
                             }
                         }
                         history = String::from("");
                     }
-                    "models()" => {
+                    "settings" => {
+                        settings.print();
+                    }
+                    "models" => {
                         print_all_models();
                     }
-                    "pwd()" => {
+                    "pwd" => {
                         run_pwd_cmd();
                     }
-                    _ => {
-                        // Parse Regex
-                        if let Some(captures) = re_cat_function.captures(&input) {
-                            parse_file(captures, true);
-                        } else if let Some(captures) = re_file_function.captures(&input) {
-                            let contents_to_use = parse_file(captures, false);
+                    "cd" => {
+                        if let Err(e) = change_dir(&call) {
+                            eprintln!("{} {}", "Error".red(), e);
+                        }
+                    }
+                    "blocks" => {
+                        if let Err(e) = print_blocks(&chat_history) {
+                            eprintln!("{} {}", "Error".red(), e);
+                        }
+                    }
+                    "save" => {
+                        if let Err(e) = save_block(&call, &chat_history) {
+                            eprintln!("{} {}", "Error".red(), e);
+                        }
+                    }
+                    "apply" => {
+                        if let Err(e) = apply_block(&call, &chat_history) {
+                            eprintln!("{} {}", "Error".red(), e);
+                        }
+                    }
+                    "files" | "tree" => {
+                        if let Err(e) = load_files(&call, &settings, &mut history, &mut files) {
+                            eprintln!("{} {}", "Error".red(), e);
+                        }
+                    }
+                    "cat" => match parse_file(&call, true, false) {
+                        Ok(_) => {}
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "file" => match parse_file(&call, false, settings.line_numbers) {
+                        Ok(contents_to_use) => {
                             history.push_str(&contents_to_use);
-                        } else if let Some(captures) = re_export_chat_function.captures(&input) {
-                            export_chat_to_ouput(captures, chat_history.get_all());
-                        } else if let Some(captures) = re_max_tokens_function.captures(&input) {
-                            max_tokens = get_max_tokens(captures);
-                            println!("Setting max tokens to {:?}", max_tokens);
-                        } else if let Some(captures) = re_set_model_function.captures(&input) {
-                            model = check_model_input(captures);
-                            println!("Setting model to {:?}", model.name());
-                        } else if let Some(captures) = re_set_temperature_function.captures(&input)
-                        {
-                            temperature = check_temperature_input(captures);
-                            println!("Setting temperature to {:?}", temperature);
-                        } else if let Some(captures) = re_cmd_function.captures(&input) {
-                            let output = run_cmd(captures);
-                            history.push_str(&output);
-                        } else {
-                            history.push_str(&input);
+                            if let Some(path) = call.text(0, "path") {
+                                let path = path.trim_start_matches("./").to_string();
+                                if !files.contains(&path) {
+                                    files.push(path);
+                                }
+                            }
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "export" => match call.required::<String>(0, "path") {
+                        Ok(file_path) => export_chat_to_ouput(
+                            &file_path,
+                            chat_history.get_all(),
+                            &settings,
+                            started,
+                        ),
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "import" => {
+                        if let Err(e) = import_chat(&call, &mut chat_history, &settings) {
+                            eprintln!("{} {}", "Error".red(), e);
+                        }
+                        candidates.clear();
+                    }
+                    "max_tokens" => match get_max_tokens(&call) {
+                        Ok(max_tokens) => {
+                            settings.max_tokens = max_tokens;
+                            println!("Setting max tokens to {:?}", settings.max_tokens);
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "model" => match call.required::<String>(0, "model") {
+                        Ok(model) => {
+                            settings.model = get_model(&model);
+                            println!("Setting model to {:?}", settings.model.name());
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "temperature" => match check_temperature_input(&call) {
+                        Ok(temperature) => {
+                            settings.temperature = temperature;
+                            println!("Setting temperature to {:?}", settings.temperature);
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "line_numbers" => match call.required::<bool>(0, "line_numbers") {
+                        Ok(line_numbers) => {
+                            settings.line_numbers = line_numbers;
+                            println!("Setting line_numbers to {:?}", settings.line_numbers);
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "fn" => match load_item(&call, settings.line_numbers) {
+                        Ok((path, text)) => {
+                            history.push_str(&text);
+                            let path = path.trim_start_matches("./").to_string();
+                            if !files.contains(&path) {
+                                files.push(path);
+                            }
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "stream" => match call.required::<bool>(0, "stream") {
+                        Ok(stream) => {
+                            settings.stream = stream;
+                            println!("Setting stream to {:?}", settings.stream);
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "retries" => match check_retries_input(&call) {
+                        Ok(attempts) => {
+                            client = client.with_max_attempts(attempts);
+                            println!("Setting retries to {:?}", attempts);
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "system" => match call.arg(0, "message") {
+                        Some(Value::Word(word)) if word == "none" => {
+                            chat_history.set_system(None);
+                            println!("Removed the system message");
+                        }
+                        Some(system) => {
+                            let system = system.to_string();
+                            println!("Setting system message to {:?}", system);
+                            chat_history.set_system(Some(system));
+                        }
+                        None => {}
+                    },
+                    "persona" => match get_persona(&call) {
+                        Ok(system) => {
+                            println!("Setting system message to {:?}", system);
+                            chat_history.set_system(Some(system));
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "session" => {
+                        let name = match call.required::<String>(0, "name") {
+                            Ok(name) => name,
+                            Err(e) => {
+                                eprintln!("{} {}", "Error".red(), e);
+                                continue;
+                            }
+                        };
+                        match &store {
+                            Some(store) if store.exists(&name) => eprintln!(
+                                "{} session {} already exists, run resume(\"{}\") to continue it",
+                                "Error".red(),
+                                name,
+                                name
+                            ),
+                            Some(store) => {
+                                match save_session(
+                                    store,
+                                    &name,
+                                    &chat_history,
+                                    &branches,
+                                    &settings,
+                                ) {
+                                    Ok(_) => {
+                                        println!(
+                                            "Saving this chat as session {}, it is saved after every command",
+                                            name.bold()
+                                        );
+                                        session = Some(name);
+                                    }
+                                    Err(e) => eprintln!("{} {}", "Error".red(), e),
+                                }
+                            }
+                            None => {
+                                eprintln!("{}", "Error: unable to find the data directory".red())
+                            }
+                        }
+                    }
+                    "resume" => {
+                        let name = match call.required::<String>(0, "name") {
+                            Ok(name) => name,
+                            Err(e) => {
+                                eprintln!("{} {}", "Error".red(), e);
+                                continue;
+                            }
+                        };
+                        let resumed = match &store {
+                            Some(store) => resume_session(
+                                store,
+                                &name,
+                                &mut chat_history,
+                                &mut branches,
+                                &mut settings,
+                            ),
+                            None => Err(io::Error::new(
+                                io::ErrorKind::NotFound,
+                                "unable to find the data directory",
+                            )),
+                        };
+                        match resumed {
+                            Ok(_) => {
+                                candidates.clear();
+                                session = Some(name);
+                            }
+                            Err(e) => {
+                                eprintln!("{} unable to resume {}: {}", "Error".red(), name, e)
+                            }
                         }
                     }
+                    "edit" => {
+                        let messages = chat_history.get_all();
+                        let index = match call.required::<usize>(0, "message") {
+                            Ok(number) if (1..=messages.len()).contains(&number) => number - 1,
+                            Ok(_) => {
+                                eprintln!(
+                                    "{} pick a message from 1 to {}, see log()",
+                                    "Error".red(),
+                                    messages.len()
+                                );
+                                continue;
+                            }
+                            Err(e) => {
+                                eprintln!("{} {}", "Error".red(), e);
+                                continue;
+                            }
+                        };
+                        let content = messages[index].content.clone().unwrap_or_default();
+                        let edited = match edit_text(&content) {
+                            Ok(edited) => edited.trim_end().to_string(),
+                            Err(e) => {
+                                eprintln!("{} unable to edit message: {}", "Error".red(), e);
+                                continue;
+                            }
+                        };
+                        if edited.is_empty() || edited == content.trim_end() {
+                            println!("The message was not changed");
+                            continue;
+                        }
+                        candidates.clear();
+                        let Some(message) = chat_history.edit(index, edited) else {
+                            continue;
+                        };
+                        match message.role.as_deref() {
+                            // Replay the conversation from the edited question
+                            Some("user") => {
+                                if let Err(e) = send_chat(
+                                    &client,
+                                    &settings,
+                                    &mut chat_history,
+                                    &mut candidates,
+                                )
+                                .await
+                                {
+                                    eprintln!(
+                                        "{}: {}, run retry() to send it again",
+                                        "Error".red(),
+                                        e
+                                    );
+                                }
+                            }
+                            Some("system") => println!("Updated the system message"),
+                            _ => println!(
+                                "Updated message {}, the messages after it were removed",
+                                index + 1
+                            ),
+                        }
+                    }
+                    "branch" => {
+                        let name = match call.required::<String>(0, "name") {
+                            Ok(name) => name,
+                            Err(e) => {
+                                eprintln!("{} {}", "Error".red(), e);
+                                continue;
+                            }
+                        };
+                        let parent = branches.current().to_string();
+                        match branches.branch(&name, &chat_history) {
+                            Ok(_) => println!(
+                                "Started branch {} from {}, checkout(\"{}\") to go back",
+                                name.bold(),
+                                parent,
+                                parent
+                            ),
+                            Err(e) => eprintln!("{} {}", "Error".red(), e),
+                        }
+                    }
+                    "checkout" => {
+                        let checkout = call
+                            .required::<String>(0, "name")
+                            .and_then(|name| branches.checkout(&name, &chat_history));
+                        match checkout {
+                            Ok(chat) => {
+                                chat_history = chat;
+                                candidates.clear();
+                                println!(
+                                    "Switched to branch {} with {} messages",
+                                    branches.current().bold(),
+                                    chat_history.get_all().len()
+                                );
+                            }
+                            Err(e) => eprintln!("{} {}", "Error".red(), e),
+                        }
+                    }
+                    "truncate" => {
+                        let args: Vec<String> = [call.text(0, "policy"), call.text(1, "turns")]
+                            .into_iter()
+                            .flatten()
+                            .collect();
+                        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+                        match TruncationPolicy::parse(&args) {
+                            Ok(policy) => {
+                                settings.truncation = policy;
+                                println!("Setting truncate to {}", policy);
+                            }
+                            Err(e) => eprintln!("{} {}", "Error".red(), e),
+                        }
+                    }
+                    "pick" => match check_pick_input(&call, candidates.len()) {
+                        Ok(number) => {
+                            // Replaces the response kept by chat()
+                            chat_history.pop();
+                            chat_history.add(candidates[number - 1].clone());
+                            println!("Keeping response [{}]", number);
+                        }
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    "cmd" => match run_cmd(&call) {
+                        Ok(output) => history.push_str(&output),
+                        Err(e) => eprintln!("{} {}", "Error".red(), e),
+                    },
+                    option => {
+                        let args = call.texts();
+                        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+                        match settings.set_option(option, &args) {
+                            Ok(_) => println!("Setting {} to {}", option, args.join(", ")),
+                            Err(e) => eprintln!("{} {}", "Error".red(), e),
+                        }
+                    }
+                }
+                if let (Some(store), Some(name)) = (&store, &session) {
+                    if let Err(e) = save_session(store, name, &chat_history, &branches, &settings) {
+                        eprintln!("{} {}: {}", "Error saving session".red(), name, e);
+                    }
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -419,3 +2027,70 @@ pub async fn run_repl() {
         }
     }
 }
+
+#[test]
+fn test_code_blocks() {
+    let reply = "Try this:\n\n```rust\nfn main() {\n\n    println!(\"hi\");\n}\n```\n\nand\n\n```\nls -la\n```\n    indented, not fenced\n";
+    let blocks = code_blocks(reply);
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].language.as_deref(), Some("rust"));
+    assert_eq!(blocks[0].code, "fn main() {\n\n    println!(\"hi\");\n}\n");
+    assert_eq!(blocks[1].language, None);
+    assert_eq!(blocks[1].code, "ls -la\n");
+}
+
+#[test]
+fn test_parse_file_range() {
+    let file = |input: &str| {
+        let call = command::parse(input, command_spec).unwrap().unwrap();
+        parse_file(&call, false, false)
+    };
+    assert_eq!(
+        file("file(\"Cargo.toml\", 2, 2)").unwrap().lines().count(),
+        1
+    );
+    assert_eq!(
+        file("cat(\"Cargo.toml\", 1, 0)"),
+        Err(String::from("end must be at least start (1)"))
+    );
+    assert!(file("file(\"Cargo.toml\", 3, 2)").is_err());
+
+    let inserted = file("file(\"Cargo.toml\", 2, 3, 4)").unwrap();
+    // Lines 2 and 3 followed by the marker
+    assert_eq!(inserted.lines().count(), 3);
+    assert_eq!(inserted.lines().last(), Some(INSERT_MARKER));
+    assert!(file("file(\"Cargo.toml\", 10, 20, 5)").is_err());
+    assert!(file("file(\"Cargo.toml\", 10, 20, 22)").is_err());
+}
+
+#[test]
+fn test_unloaded_paths() {
+    let patch = |paths: &[(&str, &str)]| {
+        let diff: Vec<String> = paths
+            .iter()
+            .map(|(old, new)| format!("--- {}\n+++ {}\n@@ -1 +1 @@\n-a\n+b\n", old, new))
+            .collect();
+        parse_patch(&diff.concat()).unwrap()
+    };
+    let loaded = [String::from("./src/main.rs")];
+    assert_eq!(
+        unloaded_paths(&patch(&[("a/src/main.rs", "b/src/main.rs")]), &loaded),
+        Ok(vec![])
+    );
+    assert_eq!(
+        unloaded_paths(&patch(&[("/dev/null", "b/src/new.rs")]), &loaded),
+        Ok(vec![String::from("src/new.rs")])
+    );
+    assert!(unloaded_paths(&patch(&[("/etc/passwd", "/etc/passwd")]), &loaded).is_err());
+    assert!(unloaded_paths(&patch(&[("a/../x", "b/../x")]), &loaded).is_err());
+    assert_eq!(
+        unloaded_paths(
+            &patch(&[
+                ("a/src/main.rs", "b/src/main.rs"),
+                ("a/src/main.rs", "b/src/main.rs")
+            ]),
+            &loaded
+        ),
+        Err(String::from("the patch changes src/main.rs more than once"))
+    );
+}
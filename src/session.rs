@@ -0,0 +1,147 @@
+use crate::branch::Branches;
+use crate::chat::GptChat;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Overrides the directory sessions are stored in
+pub const SESSION_DIR_ENV: &str = "GPTSHELL_SESSION_DIR";
+
+// A named chat and the settings it was using, stored as one json file per session
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub name: String,
+    pub chat: GptChat,
+    // Added after the first sessions were saved
+    #[serde(default)]
+    pub branches: Branches,
+    pub model: String,
+    pub temperature: f64,
+    pub max_tokens: i32,
+}
+
+// Sessions are stored under the XDG data directory i.e ~/.local/share/gptshell/sessions
+pub fn session_dir() -> Option<PathBuf> {
+    match env::var(SESSION_DIR_ENV) {
+        Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => dirs::data_dir().map(|dir| dir.join("gptshell").join("sessions")),
+    }
+}
+
+// Names become file names, so they are limited to letters, numbers, - and _
+pub fn check_session_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} is not a valid session name, use letters, numbers, - and _",
+                name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: &Path) -> SessionStore {
+        SessionStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn from_env() -> Option<SessionStore> {
+        session_dir().map(|dir| SessionStore::new(&dir))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        check_session_name(name)?;
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.path(name).map(|path| path.is_file()).unwrap_or(false)
+    }
+
+    pub fn save(&self, session: &Session) -> io::Result<()> {
+        let path = self.path(&session.name)?;
+        fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_string_pretty(session)?;
+        fs::write(path, json)
+    }
+
+    pub fn load(&self, name: &str) -> io::Result<Session> {
+        let json = fs::read_to_string(self.path(name)?)?;
+        let mut session: Session = serde_json::from_str(&json)?;
+        // The file name wins if the file was renamed
+        session.name = name.to_string();
+        Ok(session)
+    }
+
+    // Names of the stored sessions, sorted
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        if !self.dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+                if let Some(stem) = path.file_stem() {
+                    names.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[test]
+fn test_session_store() {
+    use crate::chat::{History, Message};
+
+    let dir = env::temp_dir().join(format!("gptshell-sessions-{}", std::process::id()));
+    let store = SessionStore::new(&dir);
+    assert!(store.list().unwrap().is_empty());
+
+    let mut chat = GptChat::new();
+    chat.set_system(Some(String::from("Be brief")));
+    chat.add(Message {
+        role: Some(String::from("user")),
+        content: Some(String::from("hi")),
+    });
+    let session = Session {
+        name: String::from("rust-review"),
+        chat,
+        branches: Branches::default(),
+        model: String::from("gpt-4"),
+        temperature: 0.2,
+        max_tokens: 500,
+    };
+    store.save(&session).unwrap();
+    assert!(store.exists("rust-review"));
+    assert_eq!(store.list().unwrap(), vec!["rust-review"]);
+
+    let loaded = store.load("rust-review").unwrap();
+    assert_eq!(loaded.model, "gpt-4");
+    assert_eq!(loaded.max_tokens, 500);
+    assert_eq!(loaded.chat.get_all().len(), 2);
+    assert_eq!(loaded.chat.system(), Some("Be brief"));
+
+    assert!(store.load("../escape").is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
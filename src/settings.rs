@@ -0,0 +1,307 @@
+use gptshell::chat::{ChatCreateCompletionParams, Message, ResponseFormat};
+use gptshell::completion::{split_insert, CodeCompletionCreateParams};
+use gptshell::models::Models;
+use gptshell::truncation::TruncationPolicy;
+use std::collections::HashMap;
+use std::str::FromStr;
+use text_colorizer::*;
+
+// Request parameters set from the REPL, used to build both chat and completion requests.
+// Optional parameters are only sent when they have been set.
+pub struct Settings {
+    pub model: Models,
+    pub max_tokens: i32,
+    pub temperature: f64,
+    pub stream: bool,
+    pub stop: Option<Vec<String>>,
+    pub n: Option<i32>,
+    pub top_p: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    pub logit_bias: Option<HashMap<String, i32>>,
+    pub user: Option<String>,
+    pub seed: Option<i64>,
+    pub response_format: Option<String>,
+    pub echo: Option<bool>,
+    pub best_of: Option<i32>,
+    pub logprobs: Option<i32>,
+    // Applied to the chat history when a chat request would not fit the model
+    pub truncation: TruncationPolicy,
+    // Prefixes the lines of loaded files with their line numbers
+    pub line_numbers: bool,
+}
+
+// Names of the REPL commands handled by `Settings::set_option`
+pub const OPTIONS: [&str; 12] = [
+    "stop",
+    "n",
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "logit_bias",
+    "user",
+    "seed",
+    "response_format",
+    "echo",
+    "best_of",
+    "logprobs",
+];
+
+fn parse_arg<T: FromStr>(name: &str, arg: &str) -> Result<T, String> {
+    arg.parse::<T>()
+        .map_err(|_| format!("{} is not a valid value for {}", arg, name))
+}
+
+fn check_range(name: &str, arg: &str, min: f64, max: f64) -> Result<f64, String> {
+    let value = parse_arg::<f64>(name, arg)?;
+    if !(min..=max).contains(&value) {
+        return Err(format!("{} must be between {} and {}", name, min, max));
+    }
+    Ok(value)
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings {
+            model: Models::Gpt35Turbo,
+            max_tokens: 300,
+            temperature: 0.7,
+            stream: true,
+            stop: None,
+            n: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            user: None,
+            seed: None,
+            response_format: None,
+            echo: None,
+            best_of: None,
+            logprobs: None,
+            truncation: TruncationPolicy::Off,
+            line_numbers: false,
+        }
+    }
+
+    pub fn chat_request(&self, messages: Vec<Message>) -> ChatCreateCompletionParams {
+        ChatCreateCompletionParams {
+            model: Some(self.model.name().to_string()),
+            messages: Some(messages),
+            temperature: Some(self.temperature),
+            max_tokens: Some(self.max_tokens),
+            // Streamed choices would be interleaved
+            stream: Some(self.stream && self.n.unwrap_or(1) == 1),
+            stop: self.stop.clone(),
+            n: self.n,
+            top_p: self.top_p,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            logit_bias: self.logit_bias.clone(),
+            user: self.user.clone(),
+            seed: self.seed,
+            response_format: self
+                .response_format
+                .clone()
+                .map(|r#type| ResponseFormat { r#type }),
+        }
+    }
+
+    // Text after an insert marker in the prompt is sent as the suffix
+    pub fn completion_request(&self, prompt: &str) -> CodeCompletionCreateParams {
+        let (prompt, suffix) = split_insert(prompt);
+        CodeCompletionCreateParams {
+            model: self.model.name().to_string(),
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            prompt: vec![prompt],
+            // Streamed choices would be interleaved, best_of cannot be streamed and logprobs are
+            // shown once the whole completion has arrived
+            stream: Some(
+                self.stream
+                    && self.n.unwrap_or(1) == 1
+                    && self.best_of.is_none()
+                    && self.logprobs.is_none(),
+            ),
+            stop: self.stop.clone(),
+            suffix,
+            echo: self.echo,
+            n: self.n,
+            top_p: self.top_p,
+            logprobs: self.logprobs,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            best_of: self.best_of,
+            logit_bias: self.logit_bias.clone(),
+            user: self.user.clone(),
+        }
+    }
+
+    // Sets one of `OPTIONS` from the arguments of the REPL command of the same name i.e
+    // top_p(0.9), a single `none` argument unsets the option
+    pub fn set_option(&mut self, name: &str, args: &[&str]) -> Result<(), String> {
+        let arg = args.first().copied().unwrap_or("");
+        let unset = args.len() == 1 && arg == "none";
+        match name {
+            "stop" if unset => self.stop = None,
+            "stop" => {
+                if args.len() > 4 {
+                    return Err(String::from("stop takes up to 4 sequences"));
+                }
+                // Escapes like "\n" have already been decoded by the command parser
+                self.stop = Some(args.iter().map(|s| s.to_string()).collect());
+            }
+            "n" if unset => self.n = None,
+            "n" => {
+                let n = parse_arg::<i32>(name, arg)?;
+                if n < 1 {
+                    return Err(String::from("n must be at least 1"));
+                }
+                self.n = Some(n);
+            }
+            "top_p" if unset => self.top_p = None,
+            "top_p" => self.top_p = Some(check_range(name, arg, 0.0, 1.0)?),
+            "presence_penalty" if unset => self.presence_penalty = None,
+            "presence_penalty" => self.presence_penalty = Some(check_range(name, arg, -2.0, 2.0)?),
+            "frequency_penalty" if unset => self.frequency_penalty = None,
+            "frequency_penalty" => {
+                self.frequency_penalty = Some(check_range(name, arg, -2.0, 2.0)?)
+            }
+            "logit_bias" if unset => self.logit_bias = None,
+            "logit_bias" => {
+                if args.len() != 2 {
+                    return Err(String::from(
+                        "logit_bias takes a token id and a bias i.e logit_bias(50256, -100)",
+                    ));
+                }
+                let token = parse_arg::<u32>(name, arg)?;
+                let bias = parse_arg::<i32>(name, args[1])?;
+                if !(-100..=100).contains(&bias) {
+                    return Err(String::from("logit_bias must be between -100 and 100"));
+                }
+                self.logit_bias
+                    .get_or_insert_with(HashMap::new)
+                    .insert(token.to_string(), bias);
+            }
+            "user" if unset => self.user = None,
+            "user" => self.user = Some(arg.to_string()),
+            "seed" if unset => self.seed = None,
+            "seed" => self.seed = Some(parse_arg(name, arg)?),
+            "response_format" if unset => self.response_format = None,
+            "response_format" => {
+                if arg != "text" && arg != "json_object" {
+                    return Err(String::from(
+                        "response_format must be \"text\" or \"json_object\"",
+                    ));
+                }
+                self.response_format = Some(arg.to_string());
+            }
+            "echo" if unset => self.echo = None,
+            "echo" => self.echo = Some(parse_arg(name, arg)?),
+            "best_of" if unset => self.best_of = None,
+            "best_of" => {
+                let best_of = parse_arg::<i32>(name, arg)?;
+                if best_of < self.n.unwrap_or(1) {
+                    return Err(String::from("best_of must be at least n"));
+                }
+                self.best_of = Some(best_of);
+            }
+            "logprobs" if unset => self.logprobs = None,
+            "logprobs" => {
+                let logprobs = parse_arg::<i32>(name, arg)?;
+                if !(0..=5).contains(&logprobs) {
+                    return Err(String::from("logprobs must be between 0 and 5"));
+                }
+                self.logprobs = Some(logprobs);
+            }
+            _ => return Err(format!("{} is not a setting", name)),
+        }
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let unset = String::from("not set");
+        let show = |value: Option<String>| value.unwrap_or_else(|| unset.clone());
+        println!("{}", "Current settings".bold());
+        println!("model: {}", self.model.name());
+        println!("max_tokens: {}", self.max_tokens);
+        println!("temperature: {}", self.temperature);
+        println!("stream: {}", self.stream);
+        println!(
+            "stop: {}",
+            show(self.stop.as_ref().map(|s| format!("{:?}", s)))
+        );
+        println!("n: {}", show(self.n.map(|n| n.to_string())));
+        println!("top_p: {}", show(self.top_p.map(|p| p.to_string())));
+        println!(
+            "presence_penalty: {}",
+            show(self.presence_penalty.map(|p| p.to_string()))
+        );
+        println!(
+            "frequency_penalty: {}",
+            show(self.frequency_penalty.map(|p| p.to_string()))
+        );
+        println!(
+            "logit_bias: {}",
+            show(self.logit_bias.as_ref().map(|b| format!("{:?}", b)))
+        );
+        println!("user: {}", show(self.user.clone()));
+        println!("seed: {}", show(self.seed.map(|s| s.to_string())));
+        println!("response_format: {}", show(self.response_format.clone()));
+        println!("echo: {}", show(self.echo.map(|e| e.to_string())));
+        println!("best_of: {}", show(self.best_of.map(|b| b.to_string())));
+        println!("logprobs: {}", show(self.logprobs.map(|l| l.to_string())));
+        println!("truncate: {}", self.truncation);
+        println!("line_numbers: {}", self.line_numbers);
+        println!();
+    }
+}
+
+#[test]
+fn test_set_option() {
+    let mut settings = Settings::new();
+    settings.set_option("stop", &["END", "\n", "\\n"]).unwrap();
+    settings
+        .set_option("logit_bias", &["50256", "-100"])
+        .unwrap();
+    settings.set_option("top_p", &["0.5"]).unwrap();
+    settings.set_option("top_p", &["none"]).unwrap();
+    assert!(settings.set_option("presence_penalty", &["3"]).is_err());
+    assert!(settings.set_option("n", &["two"]).is_err());
+
+    let request = settings.chat_request(vec![]);
+    assert_eq!(
+        request.stop,
+        Some(vec![
+            String::from("END"),
+            String::from("\n"),
+            String::from("\\n")
+        ])
+    );
+    assert_eq!(request.logit_bias.unwrap().get("50256"), Some(&-100));
+    assert_eq!(request.top_p, None);
+    assert_eq!(request.presence_penalty, None);
+    assert_eq!(request.stream, Some(true));
+
+    settings.set_option("n", &["2"]).unwrap();
+    assert_eq!(settings.chat_request(vec![]).stream, Some(false));
+}
+
+#[test]
+fn test_completion_request_insert() {
+    let mut settings = Settings::new();
+    settings.set_option("echo", &["true"]).unwrap();
+    let request = settings.completion_request("fn main() {\n[insert]\n}");
+    assert_eq!(request.prompt, vec![String::from("fn main() {\n")]);
+    assert_eq!(request.suffix, Some(String::from("\n}")));
+    assert_eq!(request.echo, Some(true));
+    assert_eq!(request.stream, Some(true));
+
+    settings.set_option("n", &["2"]).unwrap();
+    assert_eq!(settings.completion_request("").stream, Some(false));
+    settings.set_option("n", &["none"]).unwrap();
+    settings.set_option("best_of", &["3"]).unwrap();
+    let request = settings.completion_request("");
+    assert_eq!(request.stream, Some(false));
+    assert_eq!(request.best_of, Some(3));
+}
@@ -0,0 +1,277 @@
+// Finds Rust items by name so fn() can load just their lines. This scans the text rather than
+// parsing it, so it still works on code that does not compile.
+
+const KEYWORDS: [&str; 8] = [
+    "fn", "struct", "enum", "union", "trait", "mod", "type", "impl",
+];
+
+// Words that can come before the item keyword i.e pub(crate) async unsafe fn
+const QUALIFIERS: [&str; 6] = ["async", "const", "default", "extern", "safe", "unsafe"];
+
+// Source with comments, strings and char literals blanked out, so the brackets in them are not
+// counted. Newlines are kept so line numbers still match.
+fn mask(source: &str) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let mut masked = String::with_capacity(source.len());
+    let mut index = 0;
+    let blank = |masked: &mut String, chars: &[char]| {
+        for c in chars {
+            masked.push(if *c == '\n' { '\n' } else { ' ' });
+        }
+    };
+    while index < chars.len() {
+        let rest = &chars[index..];
+        let length = match rest {
+            ['/', '/', ..] => rest.iter().take_while(|c| **c != '\n').count(),
+            ['/', '*', ..] => {
+                let mut depth = 0;
+                let mut end = 0;
+                while end < rest.len() {
+                    match &rest[end..] {
+                        ['/', '*', ..] => {
+                            depth += 1;
+                            end += 2;
+                        }
+                        ['*', '/', ..] => {
+                            depth -= 1;
+                            end += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => end += 1,
+                    }
+                }
+                end.min(rest.len())
+            }
+            // Raw strings i.e r#"{"#, but not raw identifiers like r#type
+            ['r', '#', ..] | ['r', '"', ..] | ['b', 'r', '#', ..] | ['b', 'r', '"', ..]
+                if (index == 0 || !is_ident(chars[index - 1])) && raw_string(rest).is_some() =>
+            {
+                raw_string(rest).unwrap_or(1)
+            }
+            ['"', ..] | ['b', '"', ..] => {
+                let start = if rest[0] == 'b' { 2 } else { 1 };
+                let mut end = start;
+                while end < rest.len() && rest[end] != '"' {
+                    end += if rest[end] == '\\' { 2 } else { 1 };
+                }
+                (end + 1).min(rest.len())
+            }
+            // A char literal rather than a lifetime i.e 'a' or '\n'
+            ['\'', '\\', ..] => {
+                let end = rest[2..].iter().position(|c| *c == '\'');
+                end.map_or(1, |end| end + 3)
+            }
+            ['\'', _, '\'', ..] => 3,
+            _ => {
+                masked.push(rest[0]);
+                index += 1;
+                continue;
+            }
+        };
+        blank(&mut masked, &rest[..length]);
+        index += length;
+    }
+    masked
+}
+
+// Length of the raw string `rest` starts with
+fn raw_string(rest: &[char]) -> Option<usize> {
+    let start = rest.iter().position(|c| *c == 'r')? + 1;
+    let hashes = rest[start..].iter().take_while(|c| **c == '#').count();
+    if rest.get(start + hashes) != Some(&'"') {
+        return None;
+    }
+    let closing = (start + hashes + 1..rest.len()).find(|end| {
+        rest[*end] == '"' && rest[end + 1..].iter().take_while(|c| **c == '#').count() >= hashes
+    });
+    Some(closing.map_or(rest.len(), |end| end + 1 + hashes))
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn ident(text: &str) -> &str {
+    let end = text.find(|c: char| !is_ident(c)).unwrap_or(text.len());
+    &text[..end]
+}
+
+// Skips generics i.e <T: Into<String>> at the start of `text`
+fn skip_generics(text: &str) -> &str {
+    let text = text.trim_start();
+    if !text.starts_with('<') {
+        return text;
+    }
+    let mut depth = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => {
+                depth -= 1;
+                if depth == 0 {
+                    return &text[index + 1..];
+                }
+            }
+            _ => {}
+        }
+    }
+    ""
+}
+
+// The keyword and name of the item a masked line starts, impl blocks are named after their type
+fn item(line: &str) -> Option<(&str, &str)> {
+    let mut rest = line.trim_start();
+    loop {
+        let word = ident(rest);
+        let next = rest[word.len()..].trim_start();
+        if word == "pub" {
+            rest = match next.strip_prefix('(') {
+                Some(scope) => scope[scope.find(')')? + 1..].trim_start(),
+                None => next,
+            };
+            continue;
+        }
+        if !QUALIFIERS.contains(&word) {
+            break;
+        }
+        rest = next;
+    }
+    let keyword = ident(rest);
+    if !KEYWORDS.contains(&keyword) {
+        return None;
+    }
+    let rest = &rest[keyword.len()..];
+    if keyword != "impl" {
+        let name = ident(rest.trim_start());
+        return (!name.is_empty()).then_some((keyword, name));
+    }
+    // impl<T> Trait<T> for path::Type<T>
+    let header = skip_generics(rest);
+    let header = header.split('{').next().unwrap_or(header);
+    let header = header.split(" where").next().unwrap_or(header);
+    let target = match header.find(" for ") {
+        Some(index) => &header[index + 5..],
+        None => header,
+    };
+    let target = target.trim().trim_start_matches(['&', '*']);
+    let target = target.split('<').next().unwrap_or(target);
+    let name = ident(target.rsplit("::").next().unwrap_or(target).trim());
+    (!name.is_empty()).then_some((keyword, name))
+}
+
+// The last line of the item starting at `start`, at its matching } or its ; when it has no body
+fn item_end(masked: &[&str], start: usize) -> usize {
+    let mut depth = 0;
+    let mut opened = false;
+    for (number, line) in masked.iter().enumerate().skip(start) {
+        for c in line.chars() {
+            match c {
+                '{' | '(' | '[' => {
+                    opened |= c == '{' && depth == 0;
+                    depth += 1;
+                }
+                '}' | ')' | ']' => {
+                    depth -= 1;
+                    if depth == 0 && opened {
+                        return number;
+                    }
+                }
+                ';' if depth == 0 => return number,
+                _ => {}
+            }
+        }
+    }
+    masked.len().saturating_sub(1)
+}
+
+// Finds `name` in lines `from..to`, `Type::method` looks in the impl and trait blocks of `Type`
+fn find_in(
+    source: &[&str],
+    masked: &[&str],
+    name: &str,
+    from: usize,
+    to: usize,
+) -> Option<(usize, usize)> {
+    let (outer, inner) = match name.split_once("::") {
+        Some((outer, inner)) => (outer, Some(inner)),
+        None => (name, None),
+    };
+    for number in from..to {
+        let Some((keyword, found)) = item(masked[number]) else {
+            continue;
+        };
+        if found != outer || (inner.is_some() && !matches!(keyword, "impl" | "trait" | "mod")) {
+            continue;
+        }
+        let end = item_end(masked, number);
+        if let Some(inner) = inner {
+            match find_in(source, masked, inner, number + 1, end) {
+                Some(span) => return Some(span),
+                None => continue,
+            }
+        }
+        // Doc comments and attributes belong to the item
+        let mut start = number;
+        while start > from {
+            let above = source[start - 1].trim_start();
+            if !(above.starts_with("///") || above.starts_with("#[") || above.starts_with("//!")) {
+                break;
+            }
+            start -= 1;
+        }
+        return Some((start, end));
+    }
+    None
+}
+
+// The 1-based first and last lines of the item called `name` i.e run_repl, Settings or
+// Settings::print
+pub fn find_item(source: &str, name: &str) -> Option<(usize, usize)> {
+    let masked = mask(source);
+    let masked: Vec<&str> = masked.lines().collect();
+    let source: Vec<&str> = source.lines().collect();
+    find_in(&source, &masked, name, 0, masked.len()).map(|(start, end)| (start + 1, end + 1))
+}
+
+#[test]
+fn test_find_item() {
+    let source = r##"use std::fmt;
+
+/// A point { with a brace in the docs
+#[derive(Debug)]
+pub(crate) struct Point {
+    x: i32,
+}
+
+struct Unit;
+
+impl<T: Into<i32>> From<T> for crate::Point {
+    fn from(value: T) -> Point {
+        let s = "}";
+        let c = '}';
+        let r = r#"{"#;
+        // }
+        Point { x: value.into() }
+    }
+}
+
+pub async unsafe fn run(items: [u8; 4]) -> Result<(), String>
+where
+    String: Clone,
+{
+    /* { nested /* } */ */
+    Ok(())
+}
+
+const fn zero() -> i32 { 0 }
+"##;
+    assert_eq!(find_item(source, "Point"), Some((3, 7)));
+    assert_eq!(find_item(source, "Unit"), Some((9, 9)));
+    assert_eq!(find_item(source, "Point::from"), Some((12, 18)));
+    assert_eq!(find_item(source, "run"), Some((21, 27)));
+    assert_eq!(find_item(source, "zero"), Some((29, 29)));
+    assert_eq!(find_item(source, "missing"), None);
+    assert_eq!(find_item(source, "Point::missing"), None);
+}
@@ -0,0 +1,83 @@
+use crate::chat::Message;
+use crate::models::Models;
+use tiktoken_rs::{cl100k_base_singleton, p50k_base_singleton};
+
+// BPE vocabularies used by the models, both are bundled with tiktoken-rs so counting works offline
+// See https://github.com/openai/tiktoken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    // gpt-4 and gpt-3.5-turbo
+    Cl100k,
+    // text-davinci and code models
+    P50k,
+}
+
+// Every chat message is wrapped in tokens marking the start of the message and the role
+const TOKENS_PER_MESSAGE: usize = 3;
+// Every reply is primed with <|start|>assistant<|message|>
+const TOKENS_PER_REPLY: usize = 3;
+
+impl Encoding {
+    pub fn for_model(model: &Models) -> Encoding {
+        match model {
+            Models::Gpt4 | Models::Gpt432k | Models::Gpt35Turbo => Encoding::Cl100k,
+            _ => Encoding::P50k,
+        }
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        let bpe = match self {
+            Encoding::Cl100k => cl100k_base_singleton(),
+            Encoding::P50k => p50k_base_singleton(),
+        };
+        let bpe = bpe.lock();
+        bpe.encode_with_special_tokens(text).len()
+    }
+
+    // Tokens a chat request with these messages uses before the response
+    // See https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+    pub fn count_messages(&self, messages: &[Message]) -> usize {
+        let mut tokens = TOKENS_PER_REPLY;
+        for message in messages {
+            tokens += TOKENS_PER_MESSAGE;
+            tokens += self.count(message.role.as_deref().unwrap_or(""));
+            tokens += self.count(message.content.as_deref().unwrap_or(""));
+        }
+        tokens
+    }
+}
+
+// Checks the prompt leaves room for `max_tokens` of response in the model's context window
+pub fn check_context(model: &Models, prompt_tokens: usize, max_tokens: i32) -> Result<(), String> {
+    let limit = model.max_tokens().max(0) as usize;
+    let total = prompt_tokens + max_tokens.max(0) as usize;
+    if total > limit {
+        return Err(format!(
+            "the request needs {} tokens ({} prompt + {} max_tokens) but {} allows {}",
+            total,
+            prompt_tokens,
+            max_tokens,
+            model.name(),
+            limit
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_count_tokens() {
+    assert_eq!(Encoding::Cl100k.count("hello world"), 2);
+    assert_eq!(Encoding::P50k.count("hello world"), 2);
+    let messages = vec![Message {
+        role: Some(String::from("user")),
+        content: Some(String::from("hello world")),
+    }];
+    // 3 per message + 1 for the role + 2 for the content + 3 for the reply
+    assert_eq!(Encoding::Cl100k.count_messages(&messages), 9);
+}
+
+#[test]
+fn test_check_context() {
+    assert!(check_context(&Models::Gpt35Turbo, 3000, 1000).is_ok());
+    assert!(check_context(&Models::Gpt35Turbo, 3500, 1000).is_err());
+}
@@ -0,0 +1,151 @@
+use crate::chat::Message;
+use chrono::{DateTime, Local};
+use pulldown_cmark::escape::escape_html;
+use pulldown_cmark::{html, Event, Options, Parser};
+use std::path::Path;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+
+// How export() writes the chat history, picked from the file extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Markdown,
+    Html,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Format {
+        let extension = Path::new(path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("md") | Some("markdown") => Format::Markdown,
+            Some("html") | Some("htm") => Format::Html,
+            _ => Format::Json,
+        }
+    }
+}
+
+// A readable record of a chat, with the settings it was held with
+pub struct Transcript<'a> {
+    pub messages: &'a [Message],
+    pub model: String,
+    pub temperature: f64,
+    pub max_tokens: i32,
+    pub started: DateTime<Local>,
+    pub exported: DateTime<Local>,
+    // Tokens the messages use, counted with the model's encoding
+    pub tokens: usize,
+}
+
+fn heading(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::from("Unknown"),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    _ = escape_html(&mut escaped, text);
+    escaped
+}
+
+impl Transcript<'_> {
+    fn metadata(&self) -> Vec<(&str, String)> {
+        vec![
+            ("Model", self.model.clone()),
+            ("Temperature", self.temperature.to_string()),
+            ("Max tokens", self.max_tokens.to_string()),
+            ("Started", self.started.format(TIME_FORMAT).to_string()),
+            ("Exported", self.exported.format(TIME_FORMAT).to_string()),
+            ("Messages", self.messages.len().to_string()),
+            ("Tokens", self.tokens.to_string()),
+        ]
+    }
+
+    // Message content is kept as written so fenced code blocks carry over
+    pub fn to_markdown(&self) -> String {
+        let mut output = String::from("# Chat transcript\n\n| | |\n|---|---|\n");
+        for (name, value) in self.metadata() {
+            output.push_str(&format!("| {} | {} |\n", name, value.replace('|', "\\|")));
+        }
+        for message in self.messages {
+            output.push_str(&format!(
+                "\n## {}\n\n{}\n",
+                heading(message.role.as_deref().unwrap_or("")),
+                message.content.as_deref().unwrap_or("").trim_end()
+            ));
+        }
+        output
+    }
+
+    // Message content is rendered as markdown, raw html in it is escaped rather than kept
+    pub fn to_html(&self) -> String {
+        let mut output = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Chat transcript</title>\n</head>\n<body>\n<h1>Chat transcript</h1>\n<table>\n");
+        for (name, value) in self.metadata() {
+            output.push_str(&format!(
+                "<tr><th>{}</th><td>{}</td></tr>\n",
+                name,
+                escape(&value)
+            ));
+        }
+        output.push_str("</table>\n");
+        for message in self.messages {
+            let role = message.role.as_deref().unwrap_or("");
+            output.push_str(&format!(
+                "<section class=\"{}\">\n<h2>{}</h2>\n",
+                escape(role),
+                heading(role)
+            ));
+            let parser = Parser::new_ext(
+                message.content.as_deref().unwrap_or(""),
+                Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+            )
+            .map(|event| match event {
+                Event::Html(html) => Event::Text(html),
+                event => event,
+            });
+            html::push_html(&mut output, parser);
+            output.push_str("</section>\n");
+        }
+        output.push_str("</body>\n</html>\n");
+        output
+    }
+}
+
+#[test]
+fn test_transcript() {
+    let messages = vec![
+        Message {
+            role: Some(String::from("user")),
+            content: Some(String::from("Write hello world <b>now</b>")),
+        },
+        Message {
+            role: Some(String::from("assistant")),
+            content: Some(String::from("```rust\nfn main() {}\n```")),
+        },
+    ];
+    let transcript = Transcript {
+        messages: &messages,
+        model: String::from("gpt-4"),
+        temperature: 0.7,
+        max_tokens: 300,
+        started: Local::now(),
+        exported: Local::now(),
+        tokens: 20,
+    };
+    let markdown = transcript.to_markdown();
+    assert!(markdown.contains("| Model | gpt-4 |"));
+    assert!(markdown.contains("## Assistant\n\n```rust\nfn main() {}\n```\n"));
+
+    let html = transcript.to_html();
+    assert!(html.contains("<tr><th>Tokens</th><td>20</td></tr>"));
+    assert!(html.contains("&lt;b&gt;now&lt;/b&gt;"));
+    assert!(html.contains("<pre><code class=\"language-rust\">fn main() {}\n</code></pre>"));
+
+    assert_eq!(Format::from_path("chat.MD"), Format::Markdown);
+    assert_eq!(Format::from_path("chat.json"), Format::Json);
+}
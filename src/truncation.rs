@@ -0,0 +1,235 @@
+use crate::chat::{ChatCreateCompletionParams, GptChat, History, Message};
+use crate::err::ApiError;
+use crate::http_client::OpenAiClient;
+use std::fmt;
+
+// Tokens the summary of older turns may use
+const SUMMARY_MAX_TOKENS: i32 = 256;
+const SUMMARY_PROMPT: &str = "Summarise the conversation below in a few sentences. Keep any names, code, decisions and open questions that are needed to continue it.";
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
+
+// What to do with the chat history when a request would not fit in the model's context window.
+// A turn is a user message and the responses to it, the turn being sent counts as one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TruncationPolicy {
+    // Refuse the request
+    Off,
+    // Drop the oldest turns until the request fits
+    DropOldest,
+    // Keep the system message and the last N turns
+    KeepLast(usize),
+    // Summarise all but the last N turns with a side request
+    Summarize(usize),
+}
+
+impl TruncationPolicy {
+    // Parses the arguments of the truncate() command i.e ["keep_last", "4"]
+    pub fn parse(args: &[&str]) -> Result<TruncationPolicy, String> {
+        let turns = || -> Result<usize, String> {
+            args.get(1)
+                .and_then(|turns| turns.trim().parse::<usize>().ok())
+                .filter(|turns| *turns > 0)
+                .ok_or_else(|| format!("{} takes a number of turns i.e 4", args[0]))
+        };
+        match args.first().map(|name| name.trim()) {
+            Some("off") | Some("none") => Ok(TruncationPolicy::Off),
+            Some("drop_oldest") => Ok(TruncationPolicy::DropOldest),
+            Some("keep_last") => Ok(TruncationPolicy::KeepLast(turns()?)),
+            Some("summarize") => Ok(TruncationPolicy::Summarize(turns()?)),
+            _ => Err(String::from(
+                "the policy must be off, drop_oldest, keep_last or summarize",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TruncationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TruncationPolicy::Off => write!(f, "off"),
+            TruncationPolicy::DropOldest => write!(f, "drop_oldest"),
+            TruncationPolicy::KeepLast(turns) => write!(f, "keep_last({})", turns),
+            TruncationPolicy::Summarize(turns) => write!(f, "summarize({})", turns),
+        }
+    }
+}
+
+// Number of messages before the last `turns` turns
+fn older_than(history: &GptChat, turns: usize) -> usize {
+    let starts = history.turn_starts();
+    if starts.len() <= turns {
+        return 0;
+    }
+    starts[starts.len() - turns]
+}
+
+fn transcript(messages: &[Message]) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        transcript.push_str(&format!(
+            "{}: {}\n",
+            message.role.as_deref().unwrap_or("user"),
+            message.content.as_deref().unwrap_or("")
+        ));
+    }
+    transcript
+}
+
+impl OpenAiClient {
+    // Asks `model` for a short summary of `messages`
+    pub async fn summarize(&self, model: &str, messages: &[Message]) -> Result<String, ApiError> {
+        let request = ChatCreateCompletionParams {
+            model: Some(model.to_string()),
+            messages: Some(vec![
+                Message {
+                    role: Some(String::from("system")),
+                    content: Some(String::from(SUMMARY_PROMPT)),
+                },
+                Message {
+                    role: Some(String::from("user")),
+                    content: Some(transcript(messages)),
+                },
+            ]),
+            max_tokens: Some(SUMMARY_MAX_TOKENS),
+            ..Default::default()
+        };
+        let response = self.chat(request).await?;
+        Ok(response
+            .candidates()
+            .into_iter()
+            .next()
+            .and_then(|message| message.content)
+            .unwrap_or_default())
+    }
+}
+
+// Applies `policy` to the history when `fits` says the messages would not fit, returning how
+// many messages were removed. The caller should check the messages fit afterwards.
+pub async fn truncate<F: Fn(&[Message]) -> bool>(
+    history: &mut GptChat,
+    policy: TruncationPolicy,
+    fits: F,
+    client: &OpenAiClient,
+    model: &str,
+) -> Result<usize, ApiError> {
+    if fits(&history.get_all()) {
+        return Ok(0);
+    }
+    let removed = match policy {
+        TruncationPolicy::Off => 0,
+        TruncationPolicy::DropOldest => {
+            let mut removed = 0;
+            while !fits(&history.get_all()) {
+                // Everything before the second turn, the turn being sent is never dropped
+                let Some(second_turn) = history.turn_starts().get(1).copied() else {
+                    break;
+                };
+                removed += history.drain_oldest(second_turn).len();
+            }
+            removed
+        }
+        TruncationPolicy::KeepLast(turns) => history.drain_oldest(older_than(history, turns)).len(),
+        TruncationPolicy::Summarize(turns) => {
+            let count = older_than(history, turns);
+            if count == 0 {
+                return Ok(0);
+            }
+            // Summarise before draining so the history is untouched if the request fails
+            let summary = client
+                .summarize(model, &history.messages()[..count])
+                .await?;
+            history.drain_oldest(count);
+            history.push_front(Message {
+                role: Some(String::from("system")),
+                content: Some(format!("{}{}", SUMMARY_PREFIX, summary)),
+            });
+            count
+        }
+    };
+    Ok(removed)
+}
+
+#[cfg(test)]
+fn history_of(turns: usize) -> GptChat {
+    let mut history = GptChat::new();
+    history.set_system(Some(String::from("Be brief")));
+    for turn in 0..turns {
+        for role in ["user", "assistant"] {
+            history.add(Message {
+                role: Some(String::from(role)),
+                content: Some(format!("{} {}", role, turn)),
+            });
+        }
+    }
+    history
+}
+
+#[tokio::test]
+async fn test_truncate_drop_oldest_and_keep_last() {
+    let client = OpenAiClient::new("test-token");
+    let mut history = history_of(4);
+    let fits = |messages: &[Message]| messages.len() <= 6;
+    let removed = truncate(
+        &mut history,
+        TruncationPolicy::DropOldest,
+        fits,
+        &client,
+        "",
+    )
+    .await
+    .unwrap();
+    assert_eq!(removed, 4);
+    assert_eq!(history.system(), Some("Be brief"));
+    assert_eq!(history.messages()[0].content.as_deref(), Some("user 2"));
+
+    let mut history = history_of(4);
+    let fits = |messages: &[Message]| messages.len() <= 2;
+    let removed = truncate(
+        &mut history,
+        TruncationPolicy::KeepLast(1),
+        fits,
+        &client,
+        "",
+    )
+    .await
+    .unwrap();
+    assert_eq!(removed, 6);
+    assert_eq!(history.messages()[0].content.as_deref(), Some("user 3"));
+}
+
+#[tokio::test]
+async fn test_truncate_summarize() {
+    let mut server = mockito::Server::new_async().await;
+    let client = OpenAiClient::new("test-token").with_base_url(&server.url());
+    let mock = server
+        .mock("POST", "/v1/chat/completions")
+        .match_body(mockito::Matcher::Regex(String::from("user 0")))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{"id": "1", "choices": [{"message": {"role": "assistant", "content": "they said hi"}}]}"#,
+        )
+        .create_async()
+        .await;
+
+    let mut history = history_of(3);
+    let fits = |messages: &[Message]| messages.len() <= 4;
+    let removed = truncate(
+        &mut history,
+        TruncationPolicy::Summarize(1),
+        fits,
+        &client,
+        "fake-model",
+    )
+    .await
+    .unwrap();
+
+    mock.assert_async().await;
+    assert_eq!(removed, 4);
+    let messages = history.get_all();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(
+        messages[1].content.as_deref(),
+        Some("Summary of the earlier conversation: they said hi")
+    );
+}